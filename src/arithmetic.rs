@@ -0,0 +1,281 @@
+use std::fmt;
+
+#[cfg(test)]
+use arbitrary::Arbitrary;
+#[cfg(feature = "bigint")]
+use num_bigint::{BigInt, Sign};
+#[cfg(feature = "bigint")]
+use num_traits::Signed;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ArithmeticError,
+    value::{Float, Value},
+};
+
+/// An arithmetic operator that can be combined with `=`, as in `x -= 1`.
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl ArithmeticOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "+",
+            ArithmeticOp::Sub => "-",
+            ArithmeticOp::Mul => "*",
+            ArithmeticOp::Div => "/",
+            ArithmeticOp::Mod => "%",
+        }
+    }
+
+    /// IEEE 754 arithmetic, which never fails: dividing by zero gives an
+    /// infinity or NaN. `%` truncates like the integer one, so the result has
+    /// the sign of `x`.
+    pub fn float(self, x: f64, y: f64) -> f64 {
+        match self {
+            ArithmeticOp::Add => x + y,
+            ArithmeticOp::Sub => x - y,
+            ArithmeticOp::Mul => x * y,
+            ArithmeticOp::Div => x / y,
+            ArithmeticOp::Mod => x % y,
+        }
+    }
+
+    /// Arithmetic on integers of any size. `/` and `%` truncate, as they do
+    /// for `i64`s.
+    #[cfg(feature = "bigint")]
+    pub fn big(self, x: BigInt, y: BigInt) -> Result<BigInt, ArithmeticError> {
+        if matches!(self, ArithmeticOp::Div | ArithmeticOp::Mod) && y.sign() == Sign::NoSign {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+        Ok(match self {
+            ArithmeticOp::Add => x + y,
+            ArithmeticOp::Sub => x - y,
+            ArithmeticOp::Mul => x * y,
+            ArithmeticOp::Div => x / y,
+            ArithmeticOp::Mod => x % y,
+        })
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// What integer arithmetic does when a result doesn't fit in an `i64`.
+/// Division by zero is an error in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Overflow is an error. The default without the `bigint` feature.
+    #[cfg_attr(not(feature = "bigint"), default)]
+    Checked,
+    /// Results wrap around at the bounds of `i64`.
+    Wrapping,
+    /// Results are clamped to `i64::MIN` and `i64::MAX`.
+    Saturating,
+    /// Results that don't fit become [`Value::BigInt`]s. The default with the
+    /// `bigint` feature.
+    #[cfg(feature = "bigint")]
+    #[cfg_attr(feature = "bigint", default)]
+    Promote,
+}
+
+impl Overflow {
+    /// Applies `op` to two numbers. Two integers give an integer, with
+    /// overflow handled as `self` says. If either is a float the other is
+    /// converted and the result is a float. `None` if either isn't a number.
+    pub fn numbers(
+        self,
+        op: ArithmeticOp,
+        x: &Value,
+        y: &Value,
+    ) -> Option<Result<Value, ArithmeticError>> {
+        if let (Value::Num(x), Value::Num(y)) = (x, y) {
+            match self.apply(op, *x, *y) {
+                // Done again below with big integers.
+                #[cfg(feature = "bigint")]
+                Err(ArithmeticError::Overflow) if self == Overflow::Promote => {}
+                res => return Some(res.map(Value::Num)),
+            }
+        }
+        #[cfg(feature = "bigint")]
+        if let (Some(x), Some(y)) = (x.as_bigint(), y.as_bigint()) {
+            return Some(op.big(x, y).map(Value::integer));
+        }
+        let (x, y) = (x.as_float()?, y.as_float()?);
+        Some(Ok(Value::Float(Float(op.float(x, y)))))
+    }
+
+    /// Unary `-` of a number, or `None` if `x` isn't one.
+    pub fn unary_minus(self, x: &Value) -> Option<Result<Value, ArithmeticError>> {
+        Some(match x {
+            Value::Num(n) => match self.neg(*n) {
+                #[cfg(feature = "bigint")]
+                Err(ArithmeticError::Overflow) if self == Overflow::Promote => {
+                    Ok(Value::integer(-BigInt::from(*n)))
+                }
+                res => res.map(Value::Num),
+            },
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => Ok(Value::integer(-n)),
+            Value::Float(x) => Ok(Value::Float(Float(-x.0))),
+            _ => return None,
+        })
+    }
+
+    /// Unary `+`, the absolute value of a number, or `None` if `x` isn't one.
+    pub fn unary_plus(self, x: &Value) -> Option<Result<Value, ArithmeticError>> {
+        Some(match x {
+            Value::Num(n) => match self.abs(*n) {
+                #[cfg(feature = "bigint")]
+                Err(ArithmeticError::Overflow) if self == Overflow::Promote => {
+                    Ok(Value::integer(BigInt::from(*n).abs()))
+                }
+                res => res.map(Value::Num),
+            },
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => Ok(Value::integer(n.abs())),
+            Value::Float(x) => Ok(Value::Float(Float(x.0.abs()))),
+            _ => return None,
+        })
+    }
+
+    pub fn apply(self, op: ArithmeticOp, x: i64, y: i64) -> Result<i64, ArithmeticError> {
+        match op {
+            ArithmeticOp::Add => self.add(x, y),
+            ArithmeticOp::Sub => self.sub(x, y),
+            ArithmeticOp::Mul => self.mul(x, y),
+            ArithmeticOp::Div => self.div(x, y),
+            ArithmeticOp::Mod => self.rem(x, y),
+        }
+    }
+
+    pub fn add(self, x: i64, y: i64) -> Result<i64, ArithmeticError> {
+        match self {
+            Overflow::Checked => x.checked_add(y).ok_or(ArithmeticError::Overflow),
+            Overflow::Wrapping => Ok(x.wrapping_add(y)),
+            Overflow::Saturating => Ok(x.saturating_add(y)),
+            #[cfg(feature = "bigint")]
+            Overflow::Promote => Overflow::Checked.add(x, y),
+        }
+    }
+
+    pub fn sub(self, x: i64, y: i64) -> Result<i64, ArithmeticError> {
+        match self {
+            Overflow::Checked => x.checked_sub(y).ok_or(ArithmeticError::Overflow),
+            Overflow::Wrapping => Ok(x.wrapping_sub(y)),
+            Overflow::Saturating => Ok(x.saturating_sub(y)),
+            #[cfg(feature = "bigint")]
+            Overflow::Promote => Overflow::Checked.sub(x, y),
+        }
+    }
+
+    pub fn mul(self, x: i64, y: i64) -> Result<i64, ArithmeticError> {
+        match self {
+            Overflow::Checked => x.checked_mul(y).ok_or(ArithmeticError::Overflow),
+            Overflow::Wrapping => Ok(x.wrapping_mul(y)),
+            Overflow::Saturating => Ok(x.saturating_mul(y)),
+            #[cfg(feature = "bigint")]
+            Overflow::Promote => Overflow::Checked.mul(x, y),
+        }
+    }
+
+    pub fn div(self, x: i64, y: i64) -> Result<i64, ArithmeticError> {
+        if y == 0 {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+        // `i64::MIN / -1` is the only quotient that overflows.
+        match self {
+            Overflow::Checked => x.checked_div(y).ok_or(ArithmeticError::Overflow),
+            Overflow::Wrapping => Ok(x.wrapping_div(y)),
+            Overflow::Saturating => Ok(x.saturating_div(y)),
+            #[cfg(feature = "bigint")]
+            Overflow::Promote => Overflow::Checked.div(x, y),
+        }
+    }
+
+    /// The remainder of `x / y`, which truncates, so the result has the sign
+    /// of `x` and `(x / y) * y + x % y == x`. It never overflows: `i64::MIN %
+    /// -1` is 0 in every mode.
+    pub fn rem(self, x: i64, y: i64) -> Result<i64, ArithmeticError> {
+        if y == 0 {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+        Ok(x.wrapping_rem(y))
+    }
+
+    pub fn neg(self, x: i64) -> Result<i64, ArithmeticError> {
+        match self {
+            Overflow::Checked => x.checked_neg().ok_or(ArithmeticError::Overflow),
+            Overflow::Wrapping => Ok(x.wrapping_neg()),
+            Overflow::Saturating => Ok(x.saturating_neg()),
+            #[cfg(feature = "bigint")]
+            Overflow::Promote => Overflow::Checked.neg(x),
+        }
+    }
+
+    /// Unary `+`, which takes the absolute value.
+    pub fn abs(self, x: i64) -> Result<i64, ArithmeticError> {
+        match self {
+            Overflow::Checked => x.checked_abs().ok_or(ArithmeticError::Overflow),
+            Overflow::Wrapping => Ok(x.wrapping_abs()),
+            Overflow::Saturating => Ok(x.saturating_abs()),
+            #[cfg(feature = "bigint")]
+            Overflow::Promote => Overflow::Checked.abs(x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Overflow;
+    use insta::assert_snapshot as test;
+
+    fn table(overflow: Overflow) -> String {
+        let cases = [
+            ("max + 1", overflow.add(i64::MAX, 1)),
+            ("min - 1", overflow.sub(i64::MIN, 1)),
+            ("max * 2", overflow.mul(i64::MAX, 2)),
+            ("min / -1", overflow.div(i64::MIN, -1)),
+            ("1 / 0", overflow.div(1, 0)),
+            ("-min", overflow.neg(i64::MIN)),
+            ("+min", overflow.abs(i64::MIN)),
+            ("7 / -2", overflow.div(7, -2)),
+            ("7 % -2", overflow.rem(7, -2)),
+            ("-7 % 2", overflow.rem(-7, 2)),
+            ("min % -1", overflow.rem(i64::MIN, -1)),
+            ("1 % 0", overflow.rem(1, 0)),
+        ];
+        cases
+            .into_iter()
+            .map(|(case, res)| match res {
+                Ok(n) => format!("{case} = {n}\n"),
+                Err(e) => format!("{case}: {e}\n"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checked() {
+        test!(table(Overflow::Checked));
+    }
+
+    #[test]
+    fn wrapping() {
+        test!(table(Overflow::Wrapping));
+    }
+
+    #[test]
+    fn saturating() {
+        test!(table(Overflow::Saturating));
+    }
+}
@@ -0,0 +1,202 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+#[cfg(test)]
+use arbitrary::Arbitrary;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::CollectionError,
+    value::{to_usize, Value},
+};
+
+/// A function that comes with the language. Builtins live behind the globals,
+/// so a global of the same name hides one. Those that take strings count and
+/// index them by Unicode scalar value, not by byte.
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Builtin {
+    /// `len(array)`, `len(map)` or `len(string)`, the number of items,
+    /// entries or characters.
+    Len,
+    /// `push(array, value)` appends in place.
+    Push,
+    /// `pop(array)` removes and returns the last item.
+    Pop,
+    /// `slice(array, start, end)`, a new array of the items from `start` up
+    /// to but not including `end`.
+    Slice,
+    /// `concat(x, y)`, a new array of the items of both.
+    Concat,
+    /// `keys(map)`, an array of the keys in order.
+    Keys,
+    /// `values(map)`, an array of the values in the order of their keys.
+    Values,
+    /// `has(map, key)`, whether `key` is in the map.
+    Has,
+    /// `substr(string, start, end)`, the characters from `start` up to but
+    /// not including `end`.
+    Substr,
+    /// `split(string, separator)`, an array of the pieces between each
+    /// `separator`, or of every character if `separator` is empty.
+    Split,
+    /// `upper(string)` in upper case.
+    Upper,
+    /// `lower(string)` in lower case.
+    Lower,
+    /// `trim(string)` without its leading and trailing whitespace.
+    Trim,
+    /// `contains(string, substring)`.
+    Contains,
+    /// `replace(string, from, to)` replaces every `from` with `to`.
+    Replace,
+}
+
+impl Builtin {
+    pub fn named(name: &str) -> Option<Builtin> {
+        Some(match name {
+            "len" => Builtin::Len,
+            "push" => Builtin::Push,
+            "pop" => Builtin::Pop,
+            "slice" => Builtin::Slice,
+            "concat" => Builtin::Concat,
+            "keys" => Builtin::Keys,
+            "values" => Builtin::Values,
+            "has" => Builtin::Has,
+            "substr" => Builtin::Substr,
+            "split" => Builtin::Split,
+            "upper" => Builtin::Upper,
+            "lower" => Builtin::Lower,
+            "trim" => Builtin::Trim,
+            "contains" => Builtin::Contains,
+            "replace" => Builtin::Replace,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Len => "len",
+            Builtin::Push => "push",
+            Builtin::Pop => "pop",
+            Builtin::Slice => "slice",
+            Builtin::Concat => "concat",
+            Builtin::Keys => "keys",
+            Builtin::Values => "values",
+            Builtin::Has => "has",
+            Builtin::Substr => "substr",
+            Builtin::Split => "split",
+            Builtin::Upper => "upper",
+            Builtin::Lower => "lower",
+            Builtin::Trim => "trim",
+            Builtin::Contains => "contains",
+            Builtin::Replace => "replace",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Len
+            | Builtin::Pop
+            | Builtin::Keys
+            | Builtin::Values
+            | Builtin::Upper
+            | Builtin::Lower
+            | Builtin::Trim => 1,
+            Builtin::Push | Builtin::Concat | Builtin::Has | Builtin::Split | Builtin::Contains => {
+                2
+            }
+            Builtin::Slice | Builtin::Substr | Builtin::Replace => 3,
+        }
+    }
+
+    /// Runs the builtin. The caller checks that there are [`Builtin::arity`]
+    /// arguments, as it does for any other function.
+    pub fn call(self, args: &[Value]) -> Result<Value, CollectionError> {
+        match (self, args) {
+            (Builtin::Len, [Value::Map(entries)]) => Ok(Value::Num(entries.borrow().len() as i64)),
+            (Builtin::Len, [Value::String(s)]) => Ok(Value::Num(s.chars().count() as i64)),
+            (Builtin::Len, [array]) => Ok(Value::Num(self.array(array)?.borrow().len() as i64)),
+            (Builtin::Push, [array, value]) => {
+                array.check_acyclic(value)?;
+                self.array(array)?.borrow_mut().push(value.clone());
+                Ok(Value::Null)
+            }
+            (Builtin::Pop, [array]) => self
+                .array(array)?
+                .borrow_mut()
+                .pop()
+                .ok_or(CollectionError::Empty),
+            (Builtin::Slice, [array, start, end]) => {
+                let items = self.array(array)?.borrow();
+                Ok(Value::array(range(&items, start, end)?.to_vec()))
+            }
+            (Builtin::Concat, [x, y]) => {
+                let mut items = self.array(x)?.borrow().clone();
+                items.extend(self.array(y)?.borrow().iter().cloned());
+                Ok(Value::array(items))
+            }
+            (Builtin::Keys, [map]) => Ok(Value::array(
+                self.map(map)?.borrow().keys().cloned().collect(),
+            )),
+            (Builtin::Values, [map]) => Ok(Value::array(
+                self.map(map)?.borrow().values().cloned().collect(),
+            )),
+            (Builtin::Has, [map, key]) => {
+                let entries = self.map(map)?.borrow();
+                Ok(Value::Bool(
+                    key.to_key().is_ok_and(|key| entries.contains_key(&key)),
+                ))
+            }
+            (Builtin::Substr, [s, start, end]) => {
+                let chars: Vec<_> = self.string(s)?.chars().collect();
+                Ok(Value::String(range(&chars, start, end)?.iter().collect()))
+            }
+            (Builtin::Split, [s, separator]) => {
+                let (s, separator) = (self.string(s)?, self.string(separator)?);
+                let pieces = if separator.is_empty() {
+                    s.chars().map(String::from).map(Value::String).collect()
+                } else {
+                    s.split(separator)
+                        .map(|piece| Value::String(piece.to_string()))
+                        .collect()
+                };
+                Ok(Value::array(pieces))
+            }
+            (Builtin::Upper, [s]) => Ok(Value::String(self.string(s)?.to_uppercase())),
+            (Builtin::Lower, [s]) => Ok(Value::String(self.string(s)?.to_lowercase())),
+            (Builtin::Trim, [s]) => Ok(Value::String(self.string(s)?.trim().to_string())),
+            (Builtin::Contains, [s, substring]) => Ok(Value::Bool(
+                self.string(s)?.contains(self.string(substring)?),
+            )),
+            (Builtin::Replace, [s, from, to]) => Ok(Value::String(
+                self.string(s)?
+                    .replace(self.string(from)?, self.string(to)?),
+            )),
+            _ => unreachable!("{} called with {} arguments", self.name(), args.len()),
+        }
+    }
+
+    fn array(self, value: &Value) -> Result<&Rc<RefCell<Vec<Value>>>, CollectionError> {
+        value.items(self.name())
+    }
+
+    fn string(self, value: &Value) -> Result<&str, CollectionError> {
+        value.string(self.name())
+    }
+
+    fn map(self, value: &Value) -> Result<&Rc<RefCell<BTreeMap<Value, Value>>>, CollectionError> {
+        value.entries(self.name())
+    }
+}
+
+/// `items[start..end]`, or an error if that's out of bounds.
+fn range<'a, T>(items: &'a [T], start: &Value, end: &Value) -> Result<&'a [T], CollectionError> {
+    let (start, end) = (start.as_index()?, end.as_index()?);
+    items
+        .get(to_usize(start)..to_usize(end))
+        .ok_or(CollectionError::InvalidRange {
+            start,
+            end,
+            len: items.len(),
+        })
+}
@@ -1,93 +1,710 @@
-use std::{error::Error, process::exit};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write as _},
+    iter,
+    rc::Rc,
+};
 
+#[cfg(test)]
+use arbitrary::{Arbitrary, Unstructured};
 use serde::{Deserialize, Serialize};
 
-use crate::{expr::Expr, stmt::Stmt, value::Value};
+use crate::{
+    arithmetic::{ArithmeticOp, Overflow},
+    builtin::Builtin,
+    error::{ArithmeticError, CollectionError, CompileError, ResolveError, RuntimeError},
+    expr::Expr,
+    resolver::Resolver,
+    stmt::Stmt,
+    value::Value,
+    vm::ExecutionResult,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Bytecode {
     Print,
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
     UnaryPlus,
     UnaryMinus,
+    Not,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     Exit,
     Literal(Value),
+    /// Collects the top `n` values of the stack into an array.
+    Array(usize),
+    /// Joins the top `n` values of the stack into a string, as an
+    /// interpolated string literal does.
+    Interpolate(usize),
+    /// Collects the top `2 * n` values of the stack, alternating keys and
+    /// values, into a map.
+    Map(usize),
+    /// Replaces an array and an index, or a map and a key, with the item
+    /// there.
+    Index,
+    /// Pops an array or map, an index or key and a value, stores the value
+    /// there and pushes it back.
+    SetIndex,
+    /// Pushes copies of the top `n` values, in order.
+    Duplicate(usize),
+    /// Moves the top value down below the `n` values under it.
+    Bury(usize),
+    Pop,
+    GetLocal(usize),
+    SetLocal(usize),
+    /// Reads and writes the variables the running function captured, by
+    /// their index in its [`Function::captures`].
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    DefineGlobal(String),
+    GetGlobal(String),
+    SetGlobal(String),
+    /// Skips the next `n` instructions.
+    Jump(usize),
+    /// Skips the next `n` instructions if the top of the stack is falsy. The
+    /// condition is left on the stack.
+    JumpIfFalse(usize),
+    /// Jumps back `n` instructions, counting from the one after the `Loop`.
+    Loop(usize),
+    /// Stand-ins for `break` and `continue`, which the compiler turns into
+    /// jumps once it knows where the loop around them ends.
+    Break,
+    Continue,
+    /// Pushes a [`Closure`] of the function, capturing the variables in its
+    /// [`Function::captures`]. Functions that don't capture anything are
+    /// plain literals.
+    Closure(Rc<Function>),
+    /// Calls the function sitting below the top `argc` values of the stack.
+    Call(usize),
+    Return,
+}
+
+/// A compiled function. Its arguments occupy the first `arity` local slots of
+/// the frame it runs in.
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub code: Vec<Bytecode>,
+    /// The variables of the code around the function that it uses.
+    pub captures: Vec<Capture>,
+}
+
+/// Where a [`Bytecode::Closure`] finds a variable to capture, in the frame
+/// that creates it.
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capture {
+    /// The local in this slot.
+    Local(usize),
+    /// The variable that the running function captured at this index.
+    Upvalue(usize),
+}
+
+/// A captured variable. It's `Open` while the variable is still on the
+/// stack, at this index, and holds the value itself once it has gone out of
+/// scope. Every closure that captured it shares it.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// A function value made by [`Bytecode::Closure`].
+#[derive(Serialize, Deserialize)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    /// A deserialized closure has lost what it captured.
+    #[serde(skip)]
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    /// Closures are equal when they have the same code and captured the same
+    /// variables, so this compares the upvalues by identity.
+    fn key(&self) -> (&Function, Vec<*const RefCell<Upvalue>>) {
+        (
+            &self.function,
+            self.upvalues.iter().map(Rc::as_ptr).collect(),
+        )
+    }
+}
+
+// A closure can capture itself, so this doesn't look at the upvalues.
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("function", &self.function)
+            .finish_non_exhaustive()
+    }
 }
 
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Closure {}
+
+impl PartialOrd for Closure {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Closure {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+impl<'a> Arbitrary<'a> for Closure {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Closure {
+            function: u.arbitrary()?,
+            upvalues: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Local {
+    name: String,
+    depth: usize,
+}
+
+/// Compiles statements into bytecode. Variables declared at the top level are
+/// globals and looked up by name; everything declared inside a block lives in
+/// a stack slot that is resolved at compile time. A function that uses a
+/// local of the code around it captures it as an [`Upvalue`].
 #[derive(Default, Debug, Clone)]
-pub struct Compiler;
+pub struct Compiler {
+    locals: Vec<Local>,
+    scope_depth: usize,
+    in_function: bool,
+    /// The compiler of the code the function being compiled is nested in.
+    enclosing: Option<Box<Compiler>>,
+    /// What the function being compiled captures so far.
+    captures: Vec<Capture>,
+    /// For each loop being compiled, how many locals were declared outside
+    /// it. `break` and `continue` pop the rest before they jump.
+    loops: Vec<usize>,
+}
 
 impl Compiler {
-    pub fn compile(&mut self, stmts: &[Stmt]) -> Vec<Bytecode> {
+    /// Compiles a program, after checking it with the [`Resolver`] so that
+    /// both VMs reject the same programs.
+    pub fn compile(&mut self, stmts: &[Stmt]) -> Result<Vec<Bytecode>, CompileError> {
+        Resolver::default().resolve(stmts)?;
+        self.compile_stmts(stmts)
+    }
+
+    fn compile_stmts(&mut self, stmts: &[Stmt]) -> Result<Vec<Bytecode>, CompileError> {
         let mut bytecode = vec![];
         for stmt in stmts {
-            match stmt {
-                Stmt::Print(expr) => {
-                    bytecode.extend(Self::eval_expr(expr));
-                    bytecode.push(Bytecode::Print);
-                }
-                Stmt::Exit(expr) => {
-                    bytecode.extend(Self::eval_expr(expr));
-                    bytecode.push(Bytecode::Exit);
+            bytecode.extend(self.compile_stmt(stmt)?);
+        }
+        Ok(bytecode)
+    }
+
+    fn compile_stmt(&mut self, stmt: &Stmt) -> Result<Vec<Bytecode>, CompileError> {
+        let mut bytecode = vec![];
+        match stmt {
+            Stmt::Print(expr) => {
+                bytecode.extend(self.eval_expr(expr)?);
+                bytecode.push(Bytecode::Print);
+            }
+            Stmt::Exit(expr) => {
+                bytecode.extend(self.eval_expr(expr)?);
+                bytecode.push(Bytecode::Exit);
+            }
+            Stmt::Expr(expr) => {
+                bytecode.extend(self.eval_expr(expr)?);
+                bytecode.push(Bytecode::Pop);
+            }
+            Stmt::If(cond, body, otherwise) => {
+                let body = self.scoped(body)?;
+                let otherwise = match otherwise {
+                    Some(otherwise) => self.scoped(otherwise)?,
+                    None => vec![],
+                };
+                bytecode.extend(self.eval_expr(cond)?);
+                bytecode.push(Bytecode::JumpIfFalse(body.len() + 2));
+                bytecode.push(Bytecode::Pop);
+                bytecode.extend(body);
+                bytecode.push(Bytecode::Jump(otherwise.len() + 1));
+                bytecode.push(Bytecode::Pop);
+                bytecode.extend(otherwise);
+            }
+            Stmt::Block(stmts) => bytecode.extend(self.scoped(stmts)?),
+            Stmt::Assign(name, expr) => {
+                bytecode.extend(self.eval_expr(expr)?);
+                bytecode.extend(self.declare(name));
+            }
+            Stmt::While(cond, body) => {
+                let cond = self.eval_expr(cond)?;
+                let body = self.loop_body(body, 0)?;
+                let loop_len = cond.len() + body.len() + 3;
+                bytecode.extend(cond);
+                bytecode.push(Bytecode::JumpIfFalse(body.len() + 2));
+                bytecode.push(Bytecode::Pop);
+                bytecode.extend(body);
+                bytecode.push(Bytecode::Loop(loop_len));
+                bytecode.push(Bytecode::Pop);
+            }
+            Stmt::For(init, cond, step, body) => bytecode.extend(self.in_scope(|compiler| {
+                compiler.for_loop(init.as_deref(), cond, step.as_ref(), body)
+            })?),
+            Stmt::Break | Stmt::Continue => {
+                let Some(&outside) = self.loops.last() else {
+                    return Err(ResolveError::OutsideLoop(stmt.to_string()).into());
+                };
+                bytecode.extend(iter::repeat_n(Bytecode::Pop, self.locals.len() - outside));
+                bytecode.push(match stmt {
+                    Stmt::Break => Bytecode::Break,
+                    _ => Bytecode::Continue,
+                });
+            }
+            Stmt::Func(name, params, body) => {
+                // Declared before its body so that it can call itself.
+                let define = self.declare(name);
+                bytecode.push(self.function(name, params, body)?);
+                bytecode.extend(define);
+            }
+            Stmt::Return(expr) => {
+                if !self.in_function {
+                    return Err(CompileError::ReturnOutsideFunction);
                 }
-                Stmt::Expr(expr) => bytecode.extend(Self::eval_expr(expr)),
-                Stmt::If(_, _) => todo!(),
-                Stmt::Block(_) => todo!(),
-                Stmt::Assign(_, _) => todo!(),
-                Stmt::Func(_, _, _) => todo!(),
-                Stmt::Return(_) => todo!(),
-                Stmt::While(_, _) => todo!(),
+                bytecode.extend(self.eval_expr(expr)?);
+                bytecode.push(Bytecode::Return);
+            }
+        }
+        Ok(bytecode)
+    }
+
+    /// Declares `name` in the current scope, returning the code that stores
+    /// the value on top of the stack in it. Like the resolver, a repeated
+    /// `let` makes a new local, so closures over the old one still see it.
+    fn declare(&mut self, name: &str) -> Vec<Bytecode> {
+        if self.scope_depth == 0 {
+            return vec![Bytecode::DefineGlobal(name.to_string())];
+        }
+        // The value stays on the stack and becomes the local's slot.
+        self.locals.push(Local {
+            name: name.to_string(),
+            depth: self.scope_depth,
+        });
+        vec![]
+    }
+
+    /// Compiles a function into the instruction that makes its value.
+    fn function(
+        &mut self,
+        name: &str,
+        params: &[String],
+        body: &[Stmt],
+    ) -> Result<Bytecode, CompileError> {
+        // The function's compiler holds on to this one while it runs, to
+        // find the variables it captures.
+        let mut compiler = Compiler {
+            scope_depth: 1,
+            in_function: true,
+            enclosing: Some(Box::new(std::mem::take(self))),
+            ..Compiler::default()
+        };
+        for param in params {
+            compiler.locals.push(Local {
+                name: param.to_string(),
+                depth: 1,
+            });
+        }
+        let code = compiler.compile_stmts(body);
+        if let Some(enclosing) = compiler.enclosing.take() {
+            *self = *enclosing;
+        }
+        let mut code = code?;
+        code.push(Bytecode::Literal(Value::Null));
+        code.push(Bytecode::Return);
+
+        let function = Rc::new(Function {
+            name: name.to_string(),
+            arity: params.len(),
+            code,
+            captures: compiler.captures,
+        });
+        Ok(if function.captures.is_empty() {
+            Bytecode::Literal(Value::Function(function))
+        } else {
+            Bytecode::Closure(function)
+        })
+    }
+
+    fn for_loop(
+        &mut self,
+        init: Option<&Stmt>,
+        cond: &Expr,
+        step: Option<&Expr>,
+        body: &[Stmt],
+    ) -> Result<Vec<Bytecode>, CompileError> {
+        let mut bytecode = match init {
+            Some(init) => self.compile_stmt(init)?,
+            None => vec![],
+        };
+        let cond = self.eval_expr(cond)?;
+        let step = match step {
+            Some(step) => {
+                let mut step = self.eval_expr(step)?;
+                step.push(Bytecode::Pop);
+                step
+            }
+            None => vec![],
+        };
+        let body = self.loop_body(body, step.len())?;
+        let loop_len = cond.len() + body.len() + step.len() + 3;
+        bytecode.extend(cond);
+        bytecode.push(Bytecode::JumpIfFalse(body.len() + step.len() + 2));
+        bytecode.push(Bytecode::Pop);
+        bytecode.extend(body);
+        bytecode.extend(step);
+        bytecode.push(Bytecode::Loop(loop_len));
+        bytecode.push(Bytecode::Pop);
+        Ok(bytecode)
+    }
+
+    /// Compiles the body of a loop that is followed by `step` instructions
+    /// before it loops back, turning its `break`s and `continue`s into jumps.
+    fn loop_body(&mut self, body: &[Stmt], step: usize) -> Result<Vec<Bytecode>, CompileError> {
+        self.loops.push(self.locals.len());
+        let body = self.scoped(body);
+        self.loops.pop();
+
+        let mut body = body?;
+        let len = body.len();
+        for (i, bytecode) in body.iter_mut().enumerate() {
+            let rest = len - i - 1;
+            match bytecode {
+                // Onto the step, or the `Loop` if there is none.
+                Bytecode::Continue => *bytecode = Bytecode::Jump(rest),
+                // Past the step, the `Loop` and the `Pop` of the condition.
+                Bytecode::Break => *bytecode = Bytecode::Jump(rest + step + 2),
+                _ => {}
             }
         }
-        bytecode
+        Ok(body)
+    }
+
+    /// Compiles `stmts` in a new scope, popping the locals it declares at the
+    /// end.
+    fn scoped(&mut self, stmts: &[Stmt]) -> Result<Vec<Bytecode>, CompileError> {
+        self.in_scope(|compiler| compiler.compile_stmts(stmts))
+    }
+
+    fn in_scope(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<Vec<Bytecode>, CompileError>,
+    ) -> Result<Vec<Bytecode>, CompileError> {
+        self.scope_depth += 1;
+        let bytecode = f(self);
+        self.scope_depth -= 1;
+
+        let mut bytecode = bytecode?;
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth > self.scope_depth)
+        {
+            self.locals.pop();
+            bytecode.push(Bytecode::Pop);
+        }
+        Ok(bytecode)
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.name == name)
+    }
+
+    /// Finds `name` among the locals of the code around the function being
+    /// compiled, capturing it through every function in between. Returns
+    /// its index in [`Function::captures`].
+    fn resolve_capture(&mut self, name: &str) -> Option<usize> {
+        let enclosing = self.enclosing.as_mut()?;
+        let capture = match enclosing.resolve_local(name) {
+            Some(slot) => Capture::Local(slot),
+            None => Capture::Upvalue(enclosing.resolve_capture(name)?),
+        };
+        Some(
+            match self.captures.iter().position(|other| *other == capture) {
+                Some(index) => index,
+                None => {
+                    self.captures.push(capture);
+                    self.captures.len() - 1
+                }
+            },
+        )
     }
 
-    fn eval_expr(expr: &Expr) -> Vec<Bytecode> {
-        match expr {
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Vec<Bytecode>, CompileError> {
+        Ok(match expr {
+            Expr::Array(items) => {
+                let mut res = vec![];
+                for item in items {
+                    res.extend(self.eval_expr(item)?);
+                }
+                res.push(Bytecode::Array(items.len()));
+                res
+            }
             Expr::Literal(value) => vec![Bytecode::Literal(value.clone())],
-            Expr::Add(x, y) => Self::bin_op(x, y, Bytecode::Add),
-            Expr::Sub(x, y) => Self::bin_op(x, y, Bytecode::Sub),
-            Expr::Mul(x, y) => Self::bin_op(x, y, Bytecode::Mul),
-            Expr::Div(x, y) => Self::bin_op(x, y, Bytecode::Div),
-            Expr::UnaryPlus(x) => Self::unary_op(x, Bytecode::UnaryPlus),
-            Expr::UnaryMinus(x) => Self::unary_op(x, Bytecode::UnaryMinus),
-            Expr::AddAssign(_, _) => todo!(),
-            Expr::Not(_) => todo!(),
-            Expr::NotEqual(_, _) => todo!(),
-            Expr::EqualEqual(_, _) => todo!(),
-            Expr::LessThan(_, _) => todo!(),
-            Expr::LessThanEqual(_, _) => todo!(),
-            Expr::GreaterThan(_, _) => todo!(),
-            Expr::GreaterThanEqual(_, _) => todo!(),
-            Expr::And(_, _) => todo!(),
-            Expr::Or(_, _) => todo!(),
-            Expr::Var(_) => todo!(),
-            Expr::Call(_, _) => todo!(),
-            Expr::FnBody(_) => todo!(),
+            Expr::Interpolation(parts) => {
+                let mut res = vec![];
+                for part in parts {
+                    res.extend(self.eval_expr(part)?);
+                }
+                res.push(Bytecode::Interpolate(parts.len()));
+                res
+            }
+            Expr::Map(entries) => {
+                let mut res = vec![];
+                for (key, value) in entries {
+                    res.extend(self.eval_expr(key)?);
+                    res.extend(self.eval_expr(value)?);
+                }
+                res.push(Bytecode::Map(entries.len()));
+                res
+            }
+            Expr::Index(array, index) => self.bin_op(array, index, Bytecode::Index)?,
+            Expr::Add(x, y) => self.bin_op(x, y, Bytecode::Add)?,
+            Expr::Sub(x, y) => self.bin_op(x, y, Bytecode::Sub)?,
+            Expr::Mul(x, y) => self.bin_op(x, y, Bytecode::Mul)?,
+            Expr::Div(x, y) => self.bin_op(x, y, Bytecode::Div)?,
+            Expr::Mod(x, y) => self.bin_op(x, y, Bytecode::Mod)?,
+            Expr::UnaryPlus(x) => self.unary_op(x, Bytecode::UnaryPlus)?,
+            Expr::UnaryMinus(x) => self.unary_op(x, Bytecode::UnaryMinus)?,
+            Expr::Not(x) => self.unary_op(x, Bytecode::Not)?,
+            Expr::NotEqual(x, y) => self.bin_op(x, y, Bytecode::NotEqual)?,
+            Expr::EqualEqual(x, y) => self.bin_op(x, y, Bytecode::Equal)?,
+            Expr::LessThan(x, y) => self.bin_op(x, y, Bytecode::Less)?,
+            Expr::LessThanEqual(x, y) => self.bin_op(x, y, Bytecode::LessEqual)?,
+            Expr::GreaterThan(x, y) => self.bin_op(x, y, Bytecode::Greater)?,
+            Expr::GreaterThanEqual(x, y) => self.bin_op(x, y, Bytecode::GreaterEqual)?,
+            Expr::And(x, y) => {
+                // A falsy left operand is the result, and skips the right one.
+                let right = self.eval_expr(y)?;
+                let mut res = self.eval_expr(x)?;
+                res.push(Bytecode::JumpIfFalse(right.len() + 1));
+                res.push(Bytecode::Pop);
+                res.extend(right);
+                res
+            }
+            Expr::Or(x, y) => {
+                let right = self.eval_expr(y)?;
+                let mut res = self.eval_expr(x)?;
+                res.push(Bytecode::JumpIfFalse(1));
+                res.push(Bytecode::Jump(right.len() + 1));
+                res.push(Bytecode::Pop);
+                res.extend(right);
+                res
+            }
+            Expr::Var(name) | Expr::Local(name, _) => vec![self.get_var(name)],
+            Expr::CompoundAssign(op, target, value) => {
+                let op = Self::arithmetic(*op);
+                match &**target {
+                    // The array and index are evaluated once, and kept below
+                    // the item for the `SetIndex`.
+                    Expr::Index(array, index) => {
+                        let mut res = self.bin_op(array, index, Bytecode::Duplicate(2))?;
+                        res.push(Bytecode::Index);
+                        res.extend(self.eval_expr(value)?);
+                        res.push(op);
+                        res.push(Bytecode::SetIndex);
+                        res
+                    }
+                    Expr::Var(name) | Expr::Local(name, _) => {
+                        let mut res = vec![self.get_var(name)];
+                        res.extend(self.eval_expr(value)?);
+                        res.push(op);
+                        res.push(self.set_var(name));
+                        res
+                    }
+                    _ => return Err(CompileError::InvalidAssignmentTarget(*target.clone())),
+                }
+            }
+            // The old value is kept below everything else the update needs.
+            Expr::Postfix(op, target) => {
+                let op = Self::arithmetic(*op);
+                match &**target {
+                    Expr::Index(array, index) => {
+                        let mut res = self.bin_op(array, index, Bytecode::Duplicate(2))?;
+                        res.extend([
+                            Bytecode::Index,
+                            Bytecode::Duplicate(1),
+                            Bytecode::Bury(3),
+                            Bytecode::Literal(Value::Num(1)),
+                            op,
+                            Bytecode::SetIndex,
+                            Bytecode::Pop,
+                        ]);
+                        res
+                    }
+                    Expr::Var(name) | Expr::Local(name, _) => vec![
+                        self.get_var(name),
+                        Bytecode::Duplicate(1),
+                        Bytecode::Literal(Value::Num(1)),
+                        op,
+                        self.set_var(name),
+                        Bytecode::Pop,
+                    ],
+                    _ => return Err(CompileError::InvalidAssignmentTarget(*target.clone())),
+                }
+            }
+            Expr::Assign(target, value) => match &**target {
+                Expr::Index(array, index) => {
+                    let mut res = self.eval_expr(array)?;
+                    res.extend(self.eval_expr(index)?);
+                    res.extend(self.eval_expr(value)?);
+                    res.push(Bytecode::SetIndex);
+                    res
+                }
+                Expr::Var(name) | Expr::Local(name, _) => {
+                    let mut res = self.eval_expr(value)?;
+                    res.push(self.set_var(name));
+                    res
+                }
+                _ => return Err(CompileError::InvalidAssignmentTarget(*target.clone())),
+            },
+            Expr::Lambda(params, body) => vec![self.function("anonymous", params, body)?],
+            Expr::Call(callee, args) => {
+                let mut res = self.eval_expr(callee)?;
+                for arg in args {
+                    res.extend(self.eval_expr(arg)?);
+                }
+                res.push(Bytecode::Call(args.len()));
+                res
+            }
+            Expr::FnBody(_) => {
+                return Err(CompileError::Unsupported(
+                    "function bodies outside of a call".to_string(),
+                ))
+            }
+        })
+    }
+
+    fn get_var(&mut self, name: &str) -> Bytecode {
+        if let Some(slot) = self.resolve_local(name) {
+            Bytecode::GetLocal(slot)
+        } else if let Some(index) = self.resolve_capture(name) {
+            Bytecode::GetUpvalue(index)
+        } else {
+            Bytecode::GetGlobal(name.to_string())
+        }
+    }
+
+    fn set_var(&mut self, name: &str) -> Bytecode {
+        match self.get_var(name) {
+            Bytecode::GetLocal(slot) => Bytecode::SetLocal(slot),
+            Bytecode::GetUpvalue(index) => Bytecode::SetUpvalue(index),
+            _ => Bytecode::SetGlobal(name.to_string()),
         }
     }
 
-    fn bin_op(x: &Expr, y: &Expr, bc: Bytecode) -> Vec<Bytecode> {
+    fn arithmetic(op: ArithmeticOp) -> Bytecode {
+        match op {
+            ArithmeticOp::Add => Bytecode::Add,
+            ArithmeticOp::Sub => Bytecode::Sub,
+            ArithmeticOp::Mul => Bytecode::Mul,
+            ArithmeticOp::Div => Bytecode::Div,
+            ArithmeticOp::Mod => Bytecode::Mod,
+        }
+    }
+    fn bin_op(&mut self, x: &Expr, y: &Expr, bc: Bytecode) -> Result<Vec<Bytecode>, CompileError> {
         let mut res = vec![];
-        res.extend(Self::eval_expr(x));
-        res.extend(Self::eval_expr(y));
+        res.extend(self.eval_expr(x)?);
+        res.extend(self.eval_expr(y)?);
         res.push(bc);
-        res
+        Ok(res)
     }
-    fn unary_op(x: &Expr, bc: Bytecode) -> Vec<Bytecode> {
+    fn unary_op(&mut self, x: &Expr, bc: Bytecode) -> Result<Vec<Bytecode>, CompileError> {
         let mut res = vec![];
-        res.extend(Self::eval_expr(x));
+        res.extend(self.eval_expr(x)?);
         res.push(bc);
-        res
+        Ok(res)
     }
 }
 
+/// Renders bytecode one instruction per line, followed by the code of every
+/// function it defines.
+pub fn disassemble(code: &[Bytecode]) -> String {
+    let mut out = String::new();
+    disassemble_into(&mut out, code);
+    out.pop();
+    out
+}
+
+fn disassemble_into(out: &mut String, code: &[Bytecode]) {
+    let mut functions = vec![];
+    for (i, bc) in code.iter().enumerate() {
+        match bc {
+            Bytecode::Literal(Value::Function(function)) => {
+                let _ = writeln!(
+                    out,
+                    "{i:>4} Literal({})",
+                    Value::Function(Rc::clone(function))
+                );
+                functions.push(function);
+            }
+            Bytecode::Closure(function) => {
+                let _ = writeln!(out, "{i:>4} Closure(<fn {}>)", function.name);
+                functions.push(function);
+            }
+            _ => {
+                let _ = writeln!(out, "{i:>4} {bc:?}");
+            }
+        }
+    }
+    for function in functions {
+        let _ = writeln!(out, "\n{}/{}:", function.name, function.arity);
+        if !function.captures.is_empty() {
+            let _ = writeln!(out, "captures {:?}", function.captures);
+        }
+        disassemble_into(out, &function.code);
+    }
+}
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct CallFrame {
+    function: Rc<Function>,
+    /// What the function captured, if it's a [`Closure`].
+    closure: Option<Rc<Closure>>,
+    ip: usize,
+    /// Where the frame's first local lives on the stack.
+    base: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct VM<W: std::io::Write> {
     stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<String, Value>,
+    /// The upvalues still pointing into the stack, ordered by their index.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    max_call_depth: usize,
+    step_limit: Option<usize>,
+    overflow: Overflow,
     writer: W,
 }
 
@@ -95,115 +712,803 @@ impl<W: std::io::Write> VM<W> {
     pub fn new(writer: W) -> Self {
         Self {
             stack: vec![],
+            frames: vec![],
+            globals: HashMap::new(),
+            open_upvalues: vec![],
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            step_limit: None,
+            overflow: Overflow::default(),
             writer,
         }
     }
 
-    fn pop_two(&mut self) -> (Value, Value) {
-        let y = self.stack.pop().expect("No item to add on stack");
-        let x = self.stack.pop().expect("No item to add on stack");
-        (x, y)
+    /// Limits how many calls may be active at once; exceeding it is an error
+    /// rather than unbounded growth of the frame stack.
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Limits how many instructions a single `eval` may execute, so a host can
+    /// bound programs that never terminate.
+    pub fn with_step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = Some(step_limit);
+        self
+    }
+
+    /// Overrides the [`Overflow`] mode, as [`crate::vm::VM::with_overflow`]
+    /// does for the tree-walker.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// The index of the instruction being executed in the current frame.
+    fn ip(&self) -> usize {
+        self.frames
+            .last()
+            .map_or(0, |frame| frame.ip.saturating_sub(1))
+    }
+
+    fn pop_two(&mut self) -> Result<(Value, Value), RuntimeError> {
+        let y = self.pop()?;
+        let x = self.pop()?;
+        Ok((x, y))
+    }
+
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack
+            .pop()
+            .ok_or(RuntimeError::StackUnderflow { ip: self.ip() })
+    }
+
+    fn peek(&self) -> Result<&Value, RuntimeError> {
+        self.stack
+            .last()
+            .ok_or(RuntimeError::StackUnderflow { ip: self.ip() })
+    }
+
+    fn compare(&mut self, f: fn(Ordering) -> bool) -> Result<(), RuntimeError> {
+        let (x, y) = self.pop_two()?;
+        self.stack.push(Value::Bool(f(x.compare(&y))));
+        Ok(())
+    }
+
+    fn local(&mut self, base: usize, slot: usize) -> Result<&mut Value, RuntimeError> {
+        let ip = self.ip();
+        self.stack
+            .get_mut(base + slot)
+            .ok_or(RuntimeError::InvalidLocal { slot, ip })
+    }
+
+    /// The upvalue for the stack slot at `index`, shared with any closure
+    /// that captured it before.
+    fn capture(&mut self, index: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self.open_upvalues.partition_point(
+            |upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open < index),
+        );
+        if let Some(upvalue) = self.open_upvalues.get(position) {
+            if matches!(*upvalue.borrow(), Upvalue::Open(open) if open == index) {
+                return Rc::clone(upvalue);
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(index)));
+        self.open_upvalues.insert(position, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Moves the values of the stack slots from `start` on into the upvalues
+    /// that point at them, before those slots are popped.
+    fn close_upvalues(&mut self, start: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let index = match *upvalue.borrow() {
+                Upvalue::Open(index) if index >= start => index,
+                _ => break,
+            };
+            let value = self.stack.get(index).cloned().unwrap_or(Value::Null);
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().expect("No item to add on stack")
+    /// The upvalue at `index` of the running closure.
+    fn upvalue(&self, index: usize) -> Result<Rc<RefCell<Upvalue>>, RuntimeError> {
+        self.frames
+            .last()
+            .and_then(|frame| frame.closure.as_ref())
+            .and_then(|closure| closure.upvalues.get(index))
+            .cloned()
+            .ok_or(RuntimeError::InvalidUpvalue {
+                index,
+                ip: self.ip(),
+            })
     }
 
-    pub fn eval(&mut self, bytecodes: &[Bytecode]) -> Result<(), Box<dyn Error>> {
-        for bc in bytecodes {
+    fn jump_to(&mut self, ip: Option<usize>) -> Result<(), RuntimeError> {
+        let ip = ip.ok_or(RuntimeError::InvalidJump { ip: self.ip() })?;
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = ip;
+        }
+        Ok(())
+    }
+
+    /// Replaces the top two values with the result of `op`, which also joins
+    /// strings for `+`.
+    fn arithmetic(&mut self, op: ArithmeticOp) -> Result<(), RuntimeError> {
+        let res = match self.pop_two()? {
+            (Value::String(mut x), Value::String(y)) if op == ArithmeticOp::Add => {
+                x.push_str(&y);
+                Value::String(x)
+            }
+            (x, y) => match self.overflow.numbers(op, &x, &y) {
+                Some(res) => res.map_err(|e| self.arithmetic_error(e))?,
+                None => return Err(self.type_mismatch(op.symbol(), x, y)),
+            },
+        };
+        self.stack.push(res);
+        Ok(())
+    }
+
+    fn unary(
+        &mut self,
+        op: fn(Overflow, &Value) -> Option<Result<Value, ArithmeticError>>,
+        symbol: &'static str,
+    ) -> Result<(), RuntimeError> {
+        let x = self.pop()?;
+        match op(self.overflow, &x) {
+            Some(res) => {
+                let res = res.map_err(|e| self.arithmetic_error(e))?;
+                self.stack.push(res);
+                Ok(())
+            }
+            None => Err(self.unsupported(symbol, x)),
+        }
+    }
+
+    /// Calls `builtin` with the values above it on the stack, replacing them
+    /// and the builtin itself at `callee` with the result.
+    fn call_builtin(&mut self, builtin: Builtin, callee: usize) -> Result<(), RuntimeError> {
+        let args = self.stack.split_off(callee + 1);
+        if args.len() != builtin.arity() {
+            return Err(RuntimeError::ArityMismatch {
+                name: builtin.name().to_string(),
+                expected: builtin.arity(),
+                got: args.len(),
+                ip: self.ip(),
+            });
+        }
+        let result = builtin.call(&args).map_err(|e| self.collection_error(e))?;
+        self.stack.truncate(callee);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn collection_error(&self, source: CollectionError) -> RuntimeError {
+        RuntimeError::Collection {
+            source,
+            ip: self.ip(),
+        }
+    }
+
+    fn arithmetic_error(&self, source: ArithmeticError) -> RuntimeError {
+        RuntimeError::Arithmetic {
+            source,
+            ip: self.ip(),
+        }
+    }
+
+    fn type_mismatch(&self, op: &'static str, left: Value, right: Value) -> RuntimeError {
+        RuntimeError::TypeMismatch {
+            op,
+            left,
+            right,
+            ip: self.ip(),
+        }
+    }
+
+    fn unsupported(&self, op: &'static str, value: Value) -> RuntimeError {
+        RuntimeError::UnsupportedValue {
+            op,
+            value,
+            ip: self.ip(),
+        }
+    }
+
+    pub fn eval(&mut self, bytecodes: &[Bytecode]) -> Result<ExecutionResult, RuntimeError> {
+        let script = Function {
+            name: "script".to_string(),
+            arity: 0,
+            code: bytecodes.to_vec(),
+            captures: vec![],
+        };
+        self.frames.push(CallFrame {
+            function: Rc::new(script),
+            closure: None,
+            ip: 0,
+            base: self.stack.len(),
+        });
+        let res = self.run();
+        if res.is_err() {
+            self.frames.clear();
+            self.stack.clear();
+            self.open_upvalues.clear();
+        }
+        res
+    }
+
+    fn run(&mut self) -> Result<ExecutionResult, RuntimeError> {
+        let mut steps = 0;
+        while let Some(frame) = self.frames.last_mut() {
+            let function = Rc::clone(&frame.function);
+            let Some(bc) = function.code.get(frame.ip) else {
+                // Only the top level runs off the end, functions always return.
+                self.frames.pop();
+                continue;
+            };
+            frame.ip += 1;
+            let (ip, base) = (frame.ip, frame.base);
+
+            steps += 1;
+            if let Some(limit) = self.step_limit {
+                if steps > limit {
+                    return Err(RuntimeError::StepLimitExceeded {
+                        limit,
+                        ip: self.ip(),
+                    });
+                }
+            }
+
             match bc {
                 Bytecode::Print => {
-                    let val = self.stack.pop().expect("Could not print value");
-                    match val {
-                        Value::Num(n) => self.writer.write_all(&n.to_ne_bytes())?,
-                        Value::String(s) => self.writer.write_all(&s.into_bytes())?,
-                        _ => todo!(),
+                    let val = self.pop()?;
+                    writeln!(self.writer, "{val}").map_err(|source| RuntimeError::Io {
+                        source,
+                        ip: self.ip(),
+                    })?;
+                }
+                Bytecode::Add => self.arithmetic(ArithmeticOp::Add)?,
+                Bytecode::Sub => self.arithmetic(ArithmeticOp::Sub)?,
+                Bytecode::Mul => self.arithmetic(ArithmeticOp::Mul)?,
+                Bytecode::Div => self.arithmetic(ArithmeticOp::Div)?,
+                Bytecode::Mod => self.arithmetic(ArithmeticOp::Mod)?,
+                Bytecode::UnaryPlus => self.unary(Overflow::unary_plus, "+")?,
+                Bytecode::UnaryMinus => self.unary(Overflow::unary_minus, "-")?,
+                Bytecode::Literal(value) => self.stack.push(value.clone()),
+                Bytecode::Exit => match self.pop()? {
+                    Value::Num(n) => {
+                        let code = i32::try_from(n).map_err(|_| RuntimeError::ExitCode {
+                            code: n,
+                            ip: self.ip(),
+                        })?;
+                        // Unwind every active call, the program is over.
+                        self.frames.clear();
+                        self.stack.clear();
+                        self.open_upvalues.clear();
+                        return Ok(ExecutionResult::Exited(code));
                     }
+                    x => return Err(self.unsupported("exit", x)),
+                },
+                Bytecode::Not => match self.pop()? {
+                    Value::Bool(b) => self.stack.push(Value::Bool(!b)),
+                    x => return Err(self.unsupported("!", x)),
+                },
+                Bytecode::Equal => self.compare(Ordering::is_eq)?,
+                Bytecode::NotEqual => self.compare(Ordering::is_ne)?,
+                Bytecode::Less => self.compare(Ordering::is_lt)?,
+                Bytecode::LessEqual => self.compare(Ordering::is_le)?,
+                Bytecode::Greater => self.compare(Ordering::is_gt)?,
+                Bytecode::GreaterEqual => self.compare(Ordering::is_ge)?,
+                Bytecode::Array(n) => {
+                    let Some(start) = self.stack.len().checked_sub(*n) else {
+                        return Err(RuntimeError::StackUnderflow { ip: self.ip() });
+                    };
+                    let items = self.stack.split_off(start);
+                    self.stack.push(Value::array(items));
                 }
-                Bytecode::Add => {
-                    let (x, y) = self.pop_two();
-                    match (x, y) {
-                        (Value::Num(x), Value::Num(y)) => {
-                            self.stack.push(Value::Num(x + y));
-                        }
-                        (Value::String(mut x), Value::String(y)) => {
-                            x.push_str(&y);
-                            self.stack.push(Value::String(x));
-                        }
-                        _ => panic!("Cannot add operands"),
+                Bytecode::Interpolate(n) => {
+                    let Some(start) = self.stack.len().checked_sub(*n) else {
+                        return Err(RuntimeError::StackUnderflow { ip: self.ip() });
+                    };
+                    let parts = self.stack.split_off(start);
+                    self.stack
+                        .push(Value::String(parts.iter().map(Value::text).collect()));
+                }
+                Bytecode::Map(n) => {
+                    let Some(start) = n
+                        .checked_mul(2)
+                        .and_then(|len| self.stack.len().checked_sub(len))
+                    else {
+                        return Err(RuntimeError::StackUnderflow { ip: self.ip() });
+                    };
+                    let mut map = BTreeMap::new();
+                    let mut entries = self.stack.split_off(start).into_iter();
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        let key = key.to_key().map_err(|e| self.collection_error(e))?;
+                        map.insert(key, value);
                     }
+                    self.stack.push(Value::map(map));
                 }
-                Bytecode::Sub => {
-                    let (x, y) = self.pop_two();
-                    match (x, y) {
-                        (Value::Num(x), Value::Num(y)) => {
-                            self.stack.push(Value::Num(x - y));
-                        }
-                        _ => panic!("Cannot sub operands"),
+                Bytecode::Index => {
+                    let (array, index) = self.pop_two()?;
+                    let item = array
+                        .get_index(&index)
+                        .map_err(|e| self.collection_error(e))?;
+                    self.stack.push(item);
+                }
+                Bytecode::SetIndex => {
+                    let value = self.pop()?;
+                    let (array, index) = self.pop_two()?;
+                    array
+                        .set_index(&index, value.clone())
+                        .map_err(|e| self.collection_error(e))?;
+                    self.stack.push(value);
+                }
+                Bytecode::Duplicate(n) => {
+                    let Some(start) = self.stack.len().checked_sub(*n) else {
+                        return Err(RuntimeError::StackUnderflow { ip: self.ip() });
+                    };
+                    self.stack.extend_from_within(start..);
+                }
+                Bytecode::Bury(n) => {
+                    let value = self.pop()?;
+                    let Some(at) = self.stack.len().checked_sub(*n) else {
+                        return Err(RuntimeError::StackUnderflow { ip: self.ip() });
+                    };
+                    self.stack.insert(at, value);
+                }
+                Bytecode::Pop => {
+                    self.close_upvalues(self.stack.len().saturating_sub(1));
+                    self.pop()?;
+                }
+                Bytecode::GetLocal(slot) => {
+                    let value = self.local(base, *slot)?.clone();
+                    self.stack.push(value);
+                }
+                Bytecode::SetLocal(slot) => {
+                    let value = self.peek()?.clone();
+                    *self.local(base, *slot)? = value;
+                }
+                Bytecode::GetUpvalue(index) => {
+                    let value = match &*self.upvalue(*index)?.borrow() {
+                        Upvalue::Open(slot) => self.stack.get(*slot).cloned(),
+                        Upvalue::Closed(value) => Some(value.clone()),
+                    };
+                    let value = value.ok_or(RuntimeError::InvalidUpvalue {
+                        index: *index,
+                        ip: self.ip(),
+                    })?;
+                    self.stack.push(value);
+                }
+                Bytecode::SetUpvalue(index) => {
+                    let value = self.peek()?.clone();
+                    let upvalue = self.upvalue(*index)?;
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => match self.stack.get_mut(*slot) {
+                            Some(local) => *local = value,
+                            None => {
+                                return Err(RuntimeError::InvalidUpvalue {
+                                    index: *index,
+                                    ip: self.ip(),
+                                })
+                            }
+                        },
+                        Upvalue::Closed(closed) => *closed = value,
                     }
                 }
-                Bytecode::Mul => {
-                    let (x, y) = self.pop_two();
-                    match (x, y) {
-                        (Value::Num(x), Value::Num(y)) => {
-                            self.stack.push(Value::Num(x * y));
-                        }
-                        _ => panic!("Cannot mul operands"),
+                Bytecode::Closure(function) => {
+                    let mut upvalues = vec![];
+                    for capture in &function.captures {
+                        upvalues.push(match capture {
+                            Capture::Local(slot) => self.capture(base + slot),
+                            Capture::Upvalue(index) => self.upvalue(*index)?,
+                        });
                     }
+                    self.stack.push(Value::BytecodeClosure(Rc::new(Closure {
+                        function: Rc::clone(function),
+                        upvalues,
+                    })));
                 }
-                Bytecode::Div => {
-                    let (x, y) = self.pop_two();
-                    match (x, y) {
-                        (Value::Num(x), Value::Num(y)) => {
-                            self.stack.push(Value::Num(x / y));
-                        }
-                        _ => panic!("Cannot div operands"),
+                Bytecode::DefineGlobal(name) => {
+                    let value = self.pop()?;
+                    self.globals.insert(name.to_string(), value);
+                }
+                Bytecode::GetGlobal(name) => match self
+                    .globals
+                    .get(name)
+                    .cloned()
+                    .or_else(|| Builtin::named(name).map(Value::Builtin))
+                {
+                    Some(value) => self.stack.push(value),
+                    None => {
+                        return Err(RuntimeError::UndefinedVariable {
+                            name: name.to_string(),
+                            ip: self.ip(),
+                        })
+                    }
+                },
+                Bytecode::SetGlobal(name) => {
+                    if !self.globals.contains_key(name) {
+                        return Err(RuntimeError::UndefinedVariable {
+                            name: name.to_string(),
+                            ip: self.ip(),
+                        });
                     }
+                    let value = self.peek()?.clone();
+                    self.globals.insert(name.to_string(), value);
                 }
-                Bytecode::UnaryPlus => {
-                    let x = self.pop();
-                    match x {
-                        Value::Num(x) => {
-                            self.stack.push(Value::Num(x.abs()));
-                        }
-                        _ => panic!("Cannot pos operand"),
+                Bytecode::Jump(n) => self.jump_to(ip.checked_add(*n))?,
+                Bytecode::JumpIfFalse(n) => {
+                    if !self.peek()?.is_truthy() {
+                        self.jump_to(ip.checked_add(*n))?;
                     }
                 }
-                Bytecode::UnaryMinus => {
-                    let x = self.pop();
-                    match x {
-                        Value::Num(x) => {
-                            self.stack.push(Value::Num(-x));
+                Bytecode::Loop(n) => self.jump_to(ip.checked_sub(*n))?,
+                Bytecode::Break | Bytecode::Continue => {
+                    return Err(RuntimeError::InvalidJump { ip: self.ip() })
+                }
+                Bytecode::Call(argc) => {
+                    let Some(callee) = self.stack.len().checked_sub(argc + 1) else {
+                        return Err(RuntimeError::StackUnderflow { ip: self.ip() });
+                    };
+                    let (function, closure) = match &self.stack[callee] {
+                        Value::Function(function) => (Rc::clone(function), None),
+                        Value::BytecodeClosure(closure) => {
+                            (Rc::clone(&closure.function), Some(Rc::clone(closure)))
+                        }
+                        Value::Builtin(builtin) => {
+                            self.call_builtin(*builtin, callee)?;
+                            continue;
                         }
-                        _ => panic!("Cannot negate operand"),
+                        value => return Err(self.unsupported("call", value.clone())),
+                    };
+                    if function.arity != *argc {
+                        return Err(RuntimeError::ArityMismatch {
+                            name: function.name.clone(),
+                            expected: function.arity,
+                            got: *argc,
+                            ip: self.ip(),
+                        });
                     }
+                    // The top level occupies the first frame.
+                    if self.frames.len() > self.max_call_depth {
+                        return Err(RuntimeError::CallDepthExceeded {
+                            max: self.max_call_depth,
+                            ip: self.ip(),
+                        });
+                    }
+                    self.frames.push(CallFrame {
+                        function,
+                        closure,
+                        ip: 0,
+                        base: callee + 1,
+                    });
                 }
-                Bytecode::Literal(value) => self.stack.push(value.clone()),
-                Bytecode::Exit => {
-                    let x = self.pop();
-                    match x {
-                        Value::Num(n) => exit(n as i32),
-                        _ => panic!("Cannot exit with non-number."),
+                Bytecode::Return => {
+                    let result = self.pop()?;
+                    if let Some(frame) = self.frames.pop() {
+                        // Drop the arguments, locals and the callee itself.
+                        self.close_upvalues(frame.base);
+                        self.stack.truncate(frame.base.saturating_sub(1));
                     }
+                    self.stack.push(result);
                 }
             }
         }
-        Ok(())
+        Ok(ExecutionResult::Completed(()))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::error::Error;
+    use std::{collections::BTreeMap, error::Error};
 
     use insta::assert_yaml_snapshot as test;
 
     use crate::{
-        bytecode::{Compiler, VM},
+        arithmetic::{ArithmeticOp, Overflow},
+        bytecode::{disassemble, Compiler, VM},
         expr::Expr,
+        parser::Parser,
         stmt::Stmt,
+        tokenizer::Tokenizer,
+        value::Value,
+        vm::ExecutionResult,
     };
 
+    fn parse(program: &str) -> Vec<Stmt> {
+        let tokens = Tokenizer::default().tokenize(program).unwrap();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    /// Runs `program` and returns the non-function globals it leaves behind.
+    fn run(program: &str) -> BTreeMap<String, Value> {
+        let bc = Compiler::default().compile(&parse(program)).unwrap();
+        let mut vm = VM::new(vec![]);
+        vm.eval(&bc).unwrap();
+        assert!(vm.stack.is_empty(), "stack not cleaned up: {:?}", vm.stack);
+        vm.globals
+            .into_iter()
+            .filter(|(_, value)| !matches!(value, Value::Function(_) | Value::BytecodeClosure(_)))
+            .collect()
+    }
+
+    #[test]
+    fn compile_control_flow() {
+        let ast = parse("let i = 0; while (i < 3) { let j = i; if (j == 1) { i += 2; } i += 1; }");
+        test!(Compiler::default().compile(&ast).unwrap());
+    }
+
+    #[test]
+    fn compile_break_continue() {
+        let ast = parse(
+            "for (let i = 0; i < 3; i++) { let x = i; if x { continue; } break; } \
+             while true { { let y = 1; break; } }",
+        );
+        insta::assert_snapshot!(disassemble(&Compiler::default().compile(&ast).unwrap()));
+    }
+
+    #[test]
+    fn compile_short_circuit() {
+        let ast = parse("print a && b; print a || b;");
+        insta::assert_snapshot!(disassemble(&Compiler::default().compile(&ast).unwrap()));
+    }
+
+    #[test]
+    fn compile_indexing() {
+        let ast = parse("let xs = [1, 2]; print xs[0]; xs[1] = 3; xs[0] *= 2;");
+        insta::assert_snapshot!(disassemble(&Compiler::default().compile(&ast).unwrap()));
+    }
+
+    #[test]
+    fn while_loop() {
+        test!(run(
+            "let i = 0; let sum = 0; while (i < 5) { sum += i; i += 1; }"
+        ));
+    }
+
+    #[test]
+    fn if_stmt() {
+        test!(run(
+            "let x = 0; if (1 < 2) { x += 1; } if (2 < 1) { x += 10; } if (\"\") { x += 100; }"
+        ));
+    }
+
+    #[test]
+    fn locals() {
+        test!(run(
+            "let x = 1; let y = 0; { let x = 10; { let x = x + 5; y += x; } y += x; let x = 100; y += x; } y += x;"
+        ));
+    }
+
+    #[test]
+    fn assignment() {
+        test!(run(
+            "let x = 1; let y = 0; { let z = 2; x = z = z + 1; { y = z; } }
+             fn reset() { x = 0; } let before = x; reset();"
+        ));
+    }
+
+    #[test]
+    fn for_loop() {
+        test!(run(
+            "let total = 0; for (let i = 0; i <= 10; i += 1) { let sq = i * i; total += sq; }"
+        ));
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        test!(run(
+            "let a = 1 <= 1; let b = 2 > 3; let c = \"a\" != \"b\"; let d = !(a == b); let e = a && b; let f = a || b;"
+        ));
+    }
+
+    #[test]
+    fn arrays() {
+        test!(run("let x = 2; let xs = [1, x, x * 2];"));
+    }
+
+    #[test]
+    fn maps() {
+        test!(run(
+            "let m = {\"b\": [1], \"a\": 2, 3: null}; m[\"b\"][0] += 1; let n = has(m, 3);"
+        ));
+    }
+
+    #[test]
+    fn collection_errors() {
+        let errors: Vec<_> = [
+            "let xs = [1]; xs[1];",
+            "let xs = [1]; xs[0 - 1] = 2;",
+            "let xs = [1]; xs[\"0\"];",
+            "let x = 1; x[0];",
+            "pop([]);",
+            "slice([1, 2], 1, 3);",
+            "len(1);",
+            "push([1]);",
+            "let m = {1: 2}; m[2];",
+            "let m = {}; m[[1]] = 2;",
+            "let xs = []; push(xs, xs);",
+            "let xs = [1]; xs[0] = [xs];",
+            "let xs = [1]; let m = {}; m[0] = xs; xs[0] = m;",
+            "let m = {}; m[1] = m;",
+            "let m = {}; let xs = [m]; m[\"xs\"] = xs;",
+            "print {{}: 1};",
+            "keys([1]);",
+        ]
+        .into_iter()
+        .map(|program| {
+            let bc = Compiler::default().compile(&parse(program)).unwrap();
+            VM::new(vec![]).eval(&bc).unwrap_err().to_string()
+        })
+        .collect();
+        test!(errors);
+    }
+
+    #[test]
+    fn recursion() {
+        test!(run(
+            "fn fib(n) { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); } let x = fib(15);"
+        ));
+    }
+
+    #[test]
+    fn function_locals() {
+        test!(run("fn add(a, b) { let c = a + b; return c; }
+             fn twice(n) { { let m = n; return add(m, m); } }
+             fn nothing() { let unused = 1; }
+             let x = twice(add(1, 2));
+             let y = nothing();"));
+    }
+
+    #[test]
+    fn return_from_loop() {
+        test!(run(
+            "fn first_over(limit) { let i = 0; while (true) { if (i * i > limit) { return i; } i += 1; } }
+             let x = first_over(50);"
+        ));
+    }
+
+    #[test]
+    fn first_class_functions() {
+        test!(run("fn twice(f, x) { return f(f(x)); }
+             fn inc(n) { return n + 1; }
+             let a = twice(inc, 1);
+             let b = twice(fn(n) { return n * 3; }, 2);
+             let c = (fn(g) { return g(5); })(inc);"));
+    }
+
+    #[test]
+    fn nested_functions_are_local() {
+        let program = "let inner = 5;
+             fn outer() { fn inner() { return 1; } return inner(); }
+             let x = outer();";
+        test!(run(program));
+    }
+
+    #[test]
+    fn closures() {
+        test!(run("fn adder(n) { return fn(x) { return x + n; }; }
+             let a = adder(1)(2);
+             fn counter() { let n = 0; return [fn() { n += 1; return n; }, fn() { return n; }]; }
+             let b = 0; { let fs = counter(); fs[0](); fs[0](); b = fs[1](); }
+             fn outer() { let x = 1; fn middle() { return fn() { return x; }; } x = 2; return middle()(); }
+             let c = outer();
+             let d = 0; { fn fact(n) { if n < 2 { return 1; } return n * fact(n - 1); } d = fact(5); }"));
+    }
+
+    #[test]
+    fn compile_closures() {
+        let ast = parse("{ let x = 1; let f = fn() { x += 1; return fn() { return x; }; }; }");
+        insta::assert_snapshot!(disassemble(&Compiler::default().compile(&ast).unwrap()));
+    }
+
+    #[test]
+    fn max_call_depth() {
+        let bc = Compiler::default()
+            .compile(&parse(
+                "fn forever(n) { return forever(n + 1); } forever(0);",
+            ))
+            .unwrap();
+        let mut vm = VM::new(vec![]).with_max_call_depth(100);
+        test!(vm.eval(&bc).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        let errors: Vec<_> = [
+            "let x = 9223372036854775807; x + 1;",
+            "let x = 0 - 9223372036854775807; x - 2;",
+            "let x = 0; 1 / x;",
+            "let x = 0 - 9223372036854775807 - 1; -x;",
+        ]
+        .into_iter()
+        .map(|program| {
+            let bc = Compiler::default().compile(&parse(program)).unwrap();
+            VM::new(vec![])
+                .with_overflow(Overflow::Checked)
+                .eval(&bc)
+                .unwrap_err()
+                .to_string()
+        })
+        .collect();
+        test!(errors);
+    }
+
+    #[test]
+    fn overflow_modes() {
+        let bc = Compiler::default()
+            .compile(&parse("let x = 9223372036854775807 + 1;"))
+            .unwrap();
+        let results: Vec<_> = [Overflow::Wrapping, Overflow::Saturating]
+            .into_iter()
+            .map(|overflow| {
+                let mut vm = VM::new(vec![]).with_overflow(overflow);
+                vm.eval(&bc).unwrap();
+                vm.globals["x"].clone()
+            })
+            .collect();
+        test!(results);
+    }
+
+    #[test]
+    fn exit_unwinds() {
+        let bc = Compiler::default()
+            .compile(&parse(
+                "fn stop(n) { while (true) { exit n; } } print 1; print stop(7); print 2;",
+            ))
+            .unwrap();
+        let mut out = vec![];
+        let mut vm = VM::new(&mut out);
+        assert_eq!(vm.eval(&bc).unwrap(), ExecutionResult::Exited(7));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        test!(String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn exit_code_out_of_range() {
+        let bc = Compiler::default()
+            .compile(&parse("exit 4294967297;"))
+            .unwrap();
+        test!(VM::new(vec![]).eval(&bc).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn arity_mismatch() {
+        let bc = Compiler::default()
+            .compile(&parse("fn one(a) { return a; } one(1, 2);"))
+            .unwrap();
+        test!(VM::new(vec![]).eval(&bc).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn return_outside_function() {
+        let err = Compiler::default()
+            .compile(&parse("return 1;"))
+            .unwrap_err();
+        test!(err.to_string());
+    }
+
+    #[test]
+    fn disassemble_functions() {
+        let bc = Compiler::default()
+            .compile(&parse(
+                "fn outer(a) { fn inner(b) { return b; } return inner(a); } print outer(1);",
+            ))
+            .unwrap();
+        insta::assert_snapshot!(disassemble(&bc));
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        let ast = vec![Stmt::Expr(Expr::CompoundAssign(
+            ArithmeticOp::Add,
+            1.into(),
+            2.into(),
+        ))];
+        test!(Compiler::default().compile(&ast).unwrap_err().to_string());
+    }
+
     #[test]
     fn test() {
         let ast = vec![
@@ -217,9 +1522,9 @@ mod tests {
             )),
         ];
 
-        let mut compiler = Compiler;
+        let mut compiler = Compiler::default();
 
-        test!(compiler.compile(&ast));
+        test!(compiler.compile(&ast).unwrap());
     }
 
     #[test]
@@ -235,13 +1540,26 @@ mod tests {
             )),
         ];
 
-        let mut compiler = Compiler;
+        let mut compiler = Compiler::default();
 
-        let bc = compiler.compile(&ast);
+        let bc = compiler.compile(&ast)?;
         let mut buf = vec![];
         VM::new(&mut buf).eval(&bc)?;
 
-        test!(buf);
+        test!(String::from_utf8(buf)?);
+        Ok(())
+    }
+
+    #[test]
+    fn print_values() -> Result<(), Box<dyn Error>> {
+        let ast = parse(
+            "fn f() {} print 1; print 0 - 2; print \"hi\"; print true; print null; print [1, \"a\", [false]]; print []; print f;",
+        );
+        let bc = Compiler::default().compile(&ast)?;
+        let mut buf = vec![];
+        VM::new(&mut buf).eval(&bc)?;
+
+        test!(String::from_utf8(buf)?);
         Ok(())
     }
 
@@ -249,16 +1567,27 @@ mod tests {
 
     #[test]
     fn no_crash() {
-        arbtest(|input| {
-            let ast: Vec<Stmt> = input.arbitrary().unwrap();
-            let mut compiler = Compiler;
-
-            let bc = compiler.compile(&ast);
-            let mut buf = vec![];
-            match VM::new(&mut buf).eval(&bc) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(arbitrary::Error::IncorrectFormat),
-            }
-        });
+        // Arbitrary ASTs can nest deeply enough to overflow the default test
+        // thread's stack, so the property runs on a thread with a bigger one.
+        std::thread::Builder::new()
+            .stack_size(256 * 1024 * 1024)
+            .spawn(|| {
+                arbtest(|input| {
+                    let ast: Vec<Stmt> = input.arbitrary()?;
+                    let mut compiler = Compiler::default();
+
+                    let Ok(bc) = compiler.compile(&ast) else {
+                        return Err(arbitrary::Error::IncorrectFormat);
+                    };
+                    let mut buf = vec![];
+                    // Runtime errors are fine, only a panic fails the test.
+                    let _ = VM::new(&mut buf).with_step_limit(10_000).eval(&bc);
+                    Ok(())
+                })
+                .run();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
     }
 }
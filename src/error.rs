@@ -1,4 +1,8 @@
-use crate::expr::Expr;
+use crate::{
+    expr::Expr,
+    tokenizer::{SourceLocation, TokenType},
+    value::Value,
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -7,6 +11,148 @@ pub enum EvalError {
     InvalidBinaryExpr(Expr, String, Expr),
     #[error("{0} {1}")]
     InvalidUnaryExpr(String, Expr),
+    #[error(transparent)]
+    Arithmetic(#[from] ArithmeticError),
+    #[error(transparent)]
+    Collection(#[from] CollectionError),
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error("{0}")]
     Error(String),
 }
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticError {
+    #[error("integer overflow")]
+    Overflow,
+    #[error("division by zero")]
+    DivisionByZero,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum CollectionError {
+    #[error("index {index} is out of bounds for an array of length {len}")]
+    OutOfBounds { index: i64, len: usize },
+    #[error("range {start}..{end} is out of bounds for length {len}")]
+    InvalidRange { start: i64, end: i64, len: usize },
+    #[error("cannot index with {0}")]
+    InvalidIndex(Value),
+    #[error("cannot pop from an empty array")]
+    Empty,
+    #[error("{0} is not a key of the map")]
+    MissingKey(Value),
+    #[error("cannot use {0} as a map key")]
+    InvalidKey(Value),
+    #[error("cannot store a collection inside itself")]
+    Cycle,
+    #[error("cannot apply {op} to {value}")]
+    Unsupported { op: &'static str, value: Value },
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum LexError {
+    #[error("{loc}: unterminated string")]
+    UnterminatedString { loc: SourceLocation },
+    #[error("{loc}: unknown escape sequence '\\{escape}'")]
+    InvalidEscape { escape: char, loc: SourceLocation },
+    #[error("{loc}: empty interpolation")]
+    EmptyInterpolation { loc: SourceLocation },
+    #[error("{loc}: unterminated comment")]
+    UnterminatedComment { loc: SourceLocation },
+    #[error("{loc}: unexpected character {c:?}")]
+    UnexpectedChar { c: char, loc: SourceLocation },
+    #[error("{loc}: integer literal {literal} is too large")]
+    IntegerOverflow {
+        literal: String,
+        loc: SourceLocation,
+    },
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("{loc}: expected {expected}, found {found:?}")]
+    UnexpectedToken {
+        expected: String,
+        found: TokenType,
+        loc: SourceLocation,
+    },
+    #[error("{loc}: expected {expected}, found end of input")]
+    UnexpectedEof {
+        expected: String,
+        loc: SourceLocation,
+    },
+    #[error("{loc}: invalid assignment target")]
+    InvalidAssignmentTarget { loc: SourceLocation },
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    #[error("'{0}' is used before it is declared")]
+    UseBeforeDeclaration(String),
+    #[error("duplicate parameter '{0}'")]
+    DuplicateParameter(String),
+    #[error("'{0}' outside of a loop")]
+    OutsideLoop(String),
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    #[error("cannot assign to {0}")]
+    InvalidAssignmentTarget(Expr),
+    #[error("cannot return from outside a function")]
+    ReturnOutsideFunction,
+    #[error("{0} is not supported by the bytecode compiler yet")]
+    Unsupported(String),
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+}
+
+/// An error raised by the bytecode VM. `ip` is the index of the failing
+/// instruction within the function that was executing.
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("instruction {ip}: stack underflow")]
+    StackUnderflow { ip: usize },
+    #[error("instruction {ip}: cannot apply {op} to {left} and {right}")]
+    TypeMismatch {
+        op: &'static str,
+        left: Value,
+        right: Value,
+        ip: usize,
+    },
+    #[error("instruction {ip}: {source}")]
+    Arithmetic { source: ArithmeticError, ip: usize },
+    #[error("instruction {ip}: {source}")]
+    Collection { source: CollectionError, ip: usize },
+    #[error("instruction {ip}: cannot apply {op} to {value}")]
+    UnsupportedValue {
+        op: &'static str,
+        value: Value,
+        ip: usize,
+    },
+    #[error("instruction {ip}: undefined variable '{name}'")]
+    UndefinedVariable { name: String, ip: usize },
+    #[error("instruction {ip}: {name} expects {expected} arguments but got {got}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        got: usize,
+        ip: usize,
+    },
+    #[error("instruction {ip}: maximum call depth of {max} exceeded")]
+    CallDepthExceeded { max: usize, ip: usize },
+    #[error("instruction {ip}: step limit of {limit} exceeded")]
+    StepLimitExceeded { limit: usize, ip: usize },
+    #[error("instruction {ip}: no local in slot {slot}")]
+    InvalidLocal { slot: usize, ip: usize },
+    #[error("instruction {ip}: no upvalue {index}")]
+    InvalidUpvalue { index: usize, ip: usize },
+    #[error("instruction {ip}: exit code {code} is out of range")]
+    ExitCode { code: i64, ip: usize },
+    #[error("instruction {ip}: jump out of bounds")]
+    InvalidJump { ip: usize },
+    #[error("instruction {ip}: {source}")]
+    Io { source: std::io::Error, ip: usize },
+}
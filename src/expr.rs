@@ -1,22 +1,43 @@
-use std::fmt;
+use std::fmt::{self, Write};
 
 #[cfg(test)]
 use arbitrary::Arbitrary;
 use serde::{Deserialize, Serialize};
 
-use crate::{stmt::Stmt, value::Value};
+use crate::{
+    arithmetic::ArithmeticOp,
+    resolver::Slot,
+    stmt::Stmt,
+    value::{escape, Value},
+};
 
 #[cfg_attr(test, derive(Arbitrary))]
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Expr {
     Literal(Value),
+    /// An array literal. Each evaluation makes a new array.
+    Array(Vec<Expr>),
+    /// A map literal, `{ key: value, ... }`. Like an array literal, each
+    /// evaluation makes a new map.
+    Map(Vec<(Expr, Expr)>),
+    /// A string literal with `${expr}`s in it, as the pieces to join. Those
+    /// that aren't strings are joined as they print.
+    Interpolation(Vec<Expr>),
+    /// `array[index]` or `map[key]`.
+    Index(Box<Expr>, Box<Expr>),
     UnaryPlus(Box<Expr>),
     UnaryMinus(Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
-    AddAssign(Box<Expr>, Box<Expr>),
+    /// `place op= value`, such as `x -= 1`.
+    CompoundAssign(ArithmeticOp, Box<Expr>, Box<Expr>),
+    /// `place++` or `place--`, which updates `place` like `place += 1` or
+    /// `place -= 1` but evaluates to its old value.
+    Postfix(ArithmeticOp, Box<Expr>),
+    Assign(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
     Not(Box<Expr>),
     NotEqual(Box<Expr>, Box<Expr>),
     EqualEqual(Box<Expr>, Box<Expr>),
@@ -27,7 +48,11 @@ pub enum Expr {
     And(Box<Expr>, Box<Expr>),
     Or(Box<Expr>, Box<Expr>),
     Var(String),
-    Call(String, Vec<Expr>),
+    /// A variable the resolver found in a local scope.
+    Local(String, Slot),
+    Call(Box<Expr>, Vec<Expr>),
+    /// An anonymous function, `fn(params) { body }`.
+    Lambda(Vec<String>, Vec<Stmt>),
     FnBody(Vec<Stmt>),
 }
 
@@ -35,10 +60,33 @@ impl fmt::Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Expr::Literal(l) => f.write_str(&l.to_string()),
+            Expr::Array(items) => {
+                let items: Vec<_> = items.iter().map(Expr::to_string).collect();
+                f.write_fmt(format_args!("[{}]", items.join(", ")))
+            }
+            Expr::Map(entries) => {
+                let entries: Vec<_> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{key}: {value}"))
+                    .collect();
+                f.write_fmt(format_args!("{{{}}}", entries.join(", ")))
+            }
+            Expr::Interpolation(parts) => {
+                f.write_char('"')?;
+                for part in parts {
+                    match part {
+                        Expr::Literal(Value::String(s)) => f.write_str(&escape(s))?,
+                        part => f.write_fmt(format_args!("${{{part}}}"))?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Expr::Index(array, index) => f.write_fmt(format_args!("{}[{}]", array, index)),
             Expr::Add(x, y) => f.write_fmt(format_args!("{} + {}", x, y)),
             Expr::Sub(x, y) => f.write_fmt(format_args!("{} - {}", x, y)),
             Expr::Mul(x, y) => f.write_fmt(format_args!("{} * {}", x, y)),
             Expr::Div(x, y) => f.write_fmt(format_args!("{} / {}", x, y)),
+            Expr::Mod(x, y) => f.write_fmt(format_args!("{} % {}", x, y)),
             Expr::Not(val) => f.write_fmt(format_args!("!{}", val)),
             Expr::EqualEqual(x, y) => f.write_fmt(format_args!("{} == {}", x, y)),
             Expr::NotEqual(x, y) => f.write_fmt(format_args!("{} != {}", x, y)),
@@ -48,26 +96,43 @@ impl fmt::Display for Expr {
             Expr::GreaterThanEqual(x, y) => f.write_fmt(format_args!("{} >= {}", x, y)),
             Expr::And(x, y) => f.write_fmt(format_args!("{} && {}", x, y)),
             Expr::Or(x, y) => f.write_fmt(format_args!("{} || {}", x, y)),
-            Expr::Var(name) => f.write_str(name),
-            Expr::Call(name, args) => {
-                let mut s = format!("{name}(");
-                for arg in args {
-                    s.push_str(&arg.to_string());
-                    s.push_str(", ");
+            Expr::Var(name) | Expr::Local(name, _) => f.write_str(name),
+            Expr::Call(callee, args) => {
+                let args: Vec<_> = args.iter().map(Expr::to_string).collect();
+                match **callee {
+                    Expr::Var(_) | Expr::Local(..) | Expr::Call(..) | Expr::Index(..) => {
+                        f.write_fmt(format_args!("{callee}({})", args.join(", ")))
+                    }
+                    _ => f.write_fmt(format_args!("({callee})({})", args.join(", "))),
                 }
-                s.pop();
-                s.pop();
-                s.push(')');
-                f.write_str(&s)
+            }
+            Expr::Lambda(params, body) => {
+                let body: Vec<_> = body.iter().map(Stmt::to_string).collect();
+                f.write_fmt(format_args!(
+                    "fn({}) {{ {} }}",
+                    params.join(", "),
+                    body.join(" ")
+                ))
             }
             Expr::FnBody(body) => f.write_fmt(format_args!("{body:?}")),
             Expr::UnaryPlus(expr) => f.write_fmt(format_args!("+{}", expr)),
             Expr::UnaryMinus(expr) => f.write_fmt(format_args!("-{}", expr)),
-            Expr::AddAssign(target, incr) => f.write_fmt(format_args!("{} += {}", target, incr)),
+            Expr::CompoundAssign(op, target, value) => {
+                f.write_fmt(format_args!("{} {}= {}", target, op, value))
+            }
+            Expr::Postfix(op, target) => f.write_fmt(format_args!("{target}{op}{op}")),
+            Expr::Assign(target, value) => f.write_fmt(format_args!("{} = {}", target, value)),
         }
     }
 }
 
+impl Expr {
+    /// Whether this can be assigned to.
+    pub fn is_place(&self) -> bool {
+        matches!(self, Expr::Var(_) | Expr::Local(..) | Expr::Index(..))
+    }
+}
+
 impl From<bool> for Expr {
     fn from(value: bool) -> Self {
         Expr::Literal(Value::Bool(value))
@@ -106,24 +171,24 @@ impl From<&str> for Expr {
 
 impl From<&[Expr]> for Box<Expr> {
     fn from(value: &[Expr]) -> Self {
-        Box::new(Expr::Literal(Value::Array(value.to_vec())))
+        Box::new(Expr::Array(value.to_vec()))
     }
 }
 
 impl From<&[Expr]> for Expr {
     fn from(value: &[Expr]) -> Self {
-        Expr::Literal(Value::Array(value.to_vec()))
+        Expr::Array(value.to_vec())
     }
 }
 
 impl From<Vec<Expr>> for Box<Expr> {
     fn from(value: Vec<Expr>) -> Self {
-        Box::new(Expr::Literal(Value::Array(value)))
+        Box::new(Expr::Array(value))
     }
 }
 
 impl From<Vec<Expr>> for Expr {
     fn from(value: Vec<Expr>) -> Self {
-        Expr::Literal(Value::Array(value))
+        Expr::Array(value)
     }
 }
@@ -1,8 +1,17 @@
+// Maps are keyed by `Value`, which has arrays and maps with interior
+// mutability, but `Value::to_key` keeps those out of every map.
+#![allow(clippy::mutable_key_type)]
+
+pub mod arithmetic;
+pub mod builtin;
 pub mod bytecode;
 pub mod error;
 pub mod expr;
 pub mod optimizer;
+pub mod parser;
 pub mod printer;
+pub mod repl;
+pub mod resolver;
 pub mod serializer;
 pub mod stmt;
 pub mod tokenizer;
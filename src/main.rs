@@ -1,28 +1,109 @@
-use clap::Parser;
-use ir::error::EvalError;
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+};
 
-#[derive(Parser, Debug)]
+use clap::{Parser as ClapParser, Subcommand};
+use ir::{
+    bytecode::{self, disassemble, Compiler},
+    optimizer::Optimizer,
+    parser::Parser,
+    printer::Printer,
+    repl::Repl,
+    serializer::Serdes,
+    stmt::Stmt,
+    tokenizer::Tokenizer,
+    vm::{ExecutionResult, VM},
+};
+
+#[derive(ClapParser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    repl: bool,
-    #[arg(short, long)]
-    file: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a program
+    Run { file: PathBuf },
+    /// Start an interactive session (the default when no command is given)
+    Repl,
+    /// Print the tokens of a program
+    Tokens { file: PathBuf },
+    /// Print the parsed program
+    Ast { file: PathBuf },
+    /// Print the bytecode a program compiles to
+    Bytecode { file: PathBuf },
+    /// Print the program after optimization
+    Optimize { file: PathBuf },
+    /// Compile a program to bytecode so it can be run later with `exec`
+    Compile {
+        file: PathBuf,
+        /// Defaults to the input file with an `irb` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run a program produced by `compile`
+    Exec { file: PathBuf },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match run(args.command.unwrap_or(Command::Repl)) {
+        Ok(ExecutionResult::Completed(())) => ExitCode::SUCCESS,
+        Ok(ExecutionResult::Exited(code)) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<ExecutionResult, Box<dyn Error>> {
+    match command {
+        Command::Run { file } => {
+            let stmts = parse(&file)?;
+            return Ok(VM::default().eval(&stmts)?);
+        }
+        Command::Repl => return Ok(Repl::new(io::stdin().lock(), io::stdout()).run()?),
+        Command::Tokens { file } => {
+            for token in Tokenizer::default().tokenize(&read(&file)?)? {
+                println!("{} {:?}", token.loc, token.token);
+            }
+        }
+        Command::Ast { file } => println!("{}", Printer::new(&parse(&file)?)),
+        Command::Bytecode { file } => {
+            let bytecode = Compiler::default().compile(&parse(&file)?)?;
+            println!("{}", disassemble(&bytecode));
+        }
+        Command::Optimize { file } => {
+            println!("{}", Printer::new(&Optimizer::optimize(&parse(&file)?)))
+        }
+        Command::Compile { file, output } => {
+            let output = output.unwrap_or_else(|| file.with_extension("irb"));
+            let bytecode = Compiler::default().compile(&parse(&file)?)?;
+            fs::write(output, Serdes::serialize(&bytecode)?)?;
+        }
+        Command::Exec { file } => {
+            let bytes =
+                fs::read(&file).map_err(|e| format!("could not read {}: {e}", file.display()))?;
+            let bytecode: Vec<_> = Serdes::deserialize(&bytes)
+                .map_err(|e| format!("{} is not a compiled program: {e}", file.display()))?;
+            return Ok(bytecode::VM::new(io::stdout()).eval(&bytecode)?);
+        }
+    }
+    Ok(ExecutionResult::Completed(()))
+}
+
+fn parse(file: &Path) -> Result<Vec<Stmt>, Box<dyn Error>> {
+    let tokens = Tokenizer::default().tokenize(&read(file)?)?;
+    Ok(Parser::new(tokens).parse()?)
 }
 
-fn main() -> Result<(), EvalError> {
-    // let args = Args::parse();
-
-    // if args.repl {
-    //     repl();
-    // } else {
-    //     match args.file {
-    //         Some(file) => {
-    //             let program = std::fs::read_to_string(file).unwrap();
-    //         }
-    //         None => Ok(()),
-    //     }
-    // }
-
-    Ok(())
+fn read(file: &Path) -> Result<String, Box<dyn Error>> {
+    Ok(fs::read_to_string(file).map_err(|e| format!("could not read {}: {e}", file.display()))?)
 }
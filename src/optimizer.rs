@@ -1,128 +1,353 @@
-use crate::{expr::Expr, stmt::Stmt, value::Value};
+use std::cmp::Ordering;
+
+use crate::{
+    arithmetic::{ArithmeticOp, Overflow},
+    error::ArithmeticError,
+    expr::Expr,
+    stmt::Stmt,
+    value::Value,
+};
 
 pub struct Optimizer;
 
 impl Optimizer {
     pub fn optimize(stmts: &[Stmt]) -> Vec<Stmt> {
-        stmts
-            .iter()
-            .cloned()
-            .map(|s| Self::optimize_stmt(&s))
-            .collect()
+        stmts.iter().map(Self::optimize_stmt).collect()
     }
 
     fn optimize_stmt(stmt: &Stmt) -> Stmt {
         match stmt {
             Stmt::Print(expr) => Stmt::Print(Self::optimize_expr(expr)),
             Stmt::Expr(expr) => Stmt::Expr(Self::optimize_expr(expr)),
-            Stmt::If(cond, body) => match Self::optimize_expr(cond) {
+            Stmt::If(cond, body, otherwise) => match Self::optimize_expr(cond) {
                 Expr::Literal(Value::Bool(true)) => Stmt::Block(body.to_vec()),
-                Expr::Literal(Value::Bool(false)) => Stmt::Expr(Expr::Literal(Value::Null)),
+                Expr::Literal(Value::Bool(false)) => match otherwise {
+                    // An `elif` can be folded in turn.
+                    Some(otherwise) => match otherwise.as_slice() {
+                        [elif @ Stmt::If(..)] => Self::optimize_stmt(elif),
+                        _ => Stmt::Block(otherwise.to_vec()),
+                    },
+                    None => Stmt::Expr(Expr::Literal(Value::Null)),
+                },
                 _ => stmt.clone(),
             },
             Stmt::Assign(s, expr) => Stmt::Assign(s.to_string(), Self::optimize_expr(expr)),
+            Stmt::For(init, cond, step, body) => match Self::optimize_expr(cond) {
+                // The loop never runs, but its initializer still does.
+                Expr::Literal(Value::Bool(false)) => {
+                    Stmt::Block(init.iter().map(|init| Self::optimize_stmt(init)).collect())
+                }
+                cond => Stmt::For(
+                    init.as_deref()
+                        .map(|init| Box::new(Self::optimize_stmt(init))),
+                    cond,
+                    step.as_ref().map(Self::optimize_expr),
+                    body.clone(),
+                ),
+            },
             _ => stmt.clone(),
         }
     }
 
     fn optimize_expr(expr: &Expr) -> Expr {
         match expr {
-            Expr::Literal(value) => match value {
-                Value::Bool(_) | Value::Num(_) | Value::String(_) | Value::Null => expr.clone(),
-                Value::Array(vec) => {
-                    let mut items = vec![];
-                    for item in vec {
-                        items.push(Self::optimize_expr(item));
-                    }
-                    Expr::Literal(Value::Array(items))
+            Expr::Array(items) => Expr::Array(items.iter().map(Self::optimize_expr).collect()),
+            Expr::Map(entries) => Expr::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| (Self::optimize_expr(key), Self::optimize_expr(value)))
+                    .collect(),
+            ),
+            // Joined now if every piece is a literal.
+            Expr::Interpolation(parts) => {
+                let parts: Vec<_> = parts.iter().map(Self::optimize_expr).collect();
+                let text = parts
+                    .iter()
+                    .map(|part| match part {
+                        Expr::Literal(value) => Some(value.text()),
+                        _ => None,
+                    })
+                    .collect::<Option<String>>();
+                match text {
+                    Some(text) => Expr::Literal(Value::String(text)),
+                    None => Expr::Interpolation(parts),
                 }
-            },
+            }
+            Expr::Index(array, index) => Expr::Index(
+                Box::new(Self::optimize_expr(array)),
+                Box::new(Self::optimize_expr(index)),
+            ),
             Expr::Add(l, r) => {
                 let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
                 match (l, r) {
-                    (Expr::Literal(Value::Num(x)), Expr::Literal(Value::Num(y))) => {
-                        Expr::Literal(Value::Num(x + y))
-                    }
                     (Expr::Literal(Value::String(mut x)), Expr::Literal(Value::String(y))) => {
                         x.push_str(&y);
                         Expr::Literal(Value::String(x))
                     }
-                    _ => expr.clone(),
-                }
-            }
-            Expr::Sub(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                match (l, r) {
-                    (Expr::Literal(Value::Num(x)), Expr::Literal(Value::Num(y))) => {
-                        Expr::Literal(Value::Num(x - y))
-                    }
-                    _ => expr.clone(),
+                    (l, r) => Self::fold_arithmetic(l, r, ArithmeticOp::Add, Expr::Add),
                 }
             }
-            Expr::Mul(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                match (l, r) {
-                    (Expr::Literal(Value::Num(x)), Expr::Literal(Value::Num(y))) => {
-                        Expr::Literal(Value::Num(x * y))
-                    }
-                    _ => expr.clone(),
-                }
-            }
-            Expr::Div(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                match (l, r) {
-                    (Expr::Literal(Value::Num(x)), Expr::Literal(Value::Num(y))) => {
-                        Expr::Literal(Value::Num(x / y))
-                    }
-                    _ => expr.clone(),
-                }
-            }
-            Expr::Not(expr) | Expr::UnaryPlus(expr) | Expr::UnaryMinus(expr) => {
-                Self::optimize_expr(expr)
-            }
+            Expr::Sub(l, r) => Self::fold_arithmetic(
+                Self::optimize_expr(l),
+                Self::optimize_expr(r),
+                ArithmeticOp::Sub,
+                Expr::Sub,
+            ),
+            Expr::Mul(l, r) => Self::fold_arithmetic(
+                Self::optimize_expr(l),
+                Self::optimize_expr(r),
+                ArithmeticOp::Mul,
+                Expr::Mul,
+            ),
+            Expr::Div(l, r) => Self::fold_arithmetic(
+                Self::optimize_expr(l),
+                Self::optimize_expr(r),
+                ArithmeticOp::Div,
+                Expr::Div,
+            ),
+            Expr::Mod(l, r) => Self::fold_arithmetic(
+                Self::optimize_expr(l),
+                Self::optimize_expr(r),
+                ArithmeticOp::Mod,
+                Expr::Mod,
+            ),
+            Expr::Not(x) => match Self::optimize_expr(x) {
+                Expr::Literal(Value::Bool(b)) => Expr::Literal(Value::Bool(!b)),
+                x => Expr::Not(Box::new(x)),
+            },
+            Expr::UnaryPlus(x) => Self::fold_unary(
+                Self::optimize_expr(x),
+                Overflow::unary_plus,
+                Expr::UnaryPlus,
+            ),
+            Expr::UnaryMinus(x) => Self::fold_unary(
+                Self::optimize_expr(x),
+                Overflow::unary_minus,
+                Expr::UnaryMinus,
+            ),
             Expr::EqualEqual(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                Expr::Literal((l == r).into())
-            }
-            Expr::NotEqual(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                Expr::Literal((l != r).into())
-            }
-            Expr::LessThan(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                Expr::Literal((l < r).into())
+                Self::fold_comparison(l, r, Ordering::is_eq, Expr::EqualEqual)
             }
+            Expr::NotEqual(l, r) => Self::fold_comparison(l, r, Ordering::is_ne, Expr::NotEqual),
+            Expr::LessThan(l, r) => Self::fold_comparison(l, r, Ordering::is_lt, Expr::LessThan),
             Expr::LessThanEqual(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                Expr::Literal((l <= r).into())
+                Self::fold_comparison(l, r, Ordering::is_le, Expr::LessThanEqual)
             }
             Expr::GreaterThan(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                Expr::Literal((l > r).into())
+                Self::fold_comparison(l, r, Ordering::is_gt, Expr::GreaterThan)
             }
             Expr::GreaterThanEqual(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                Expr::Literal((l >= r).into())
+                Self::fold_comparison(l, r, Ordering::is_ge, Expr::GreaterThanEqual)
             }
+            // A constant left operand decides whether the right one runs.
             Expr::And(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                match (l, r) {
-                    (Expr::Literal(l), Expr::Literal(r)) => {
-                        Expr::Literal(Value::Bool(l.is_truthy() && r.is_truthy()))
-                    }
-                    _ => expr.clone(),
+                let l = Self::optimize_expr(l);
+                match truthiness(&l) {
+                    Some(false) => l,
+                    Some(true) => Self::optimize_expr(r),
+                    None => Expr::And(Box::new(l), Box::new(Self::optimize_expr(r))),
                 }
             }
             Expr::Or(l, r) => {
-                let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
-                match (l, r) {
-                    (Expr::Literal(l), Expr::Literal(r)) => {
-                        Expr::Literal(Value::Bool(l.is_truthy() || r.is_truthy()))
-                    }
-                    _ => expr.clone(),
+                let l = Self::optimize_expr(l);
+                match truthiness(&l) {
+                    Some(true) => l,
+                    Some(false) => Self::optimize_expr(r),
+                    None => Expr::Or(Box::new(l), Box::new(Self::optimize_expr(r))),
                 }
             }
-            Expr::Var(_) | Expr::Call(..) | Expr::FnBody(_) | _ => expr.clone(),
+            Expr::Assign(target, value) => Expr::Assign(
+                Box::new(Self::optimize_expr(target)),
+                Box::new(Self::optimize_expr(value)),
+            ),
+            Expr::CompoundAssign(op, target, value) => Expr::CompoundAssign(
+                *op,
+                Box::new(Self::optimize_expr(target)),
+                Box::new(Self::optimize_expr(value)),
+            ),
+            Expr::Postfix(op, target) => Expr::Postfix(*op, Box::new(Self::optimize_expr(target))),
+            Expr::Literal(_)
+            | Expr::Var(_)
+            | Expr::Local(..)
+            | Expr::Call(..)
+            | Expr::Lambda(..)
+            | Expr::FnBody(_) => expr.clone(),
+        }
+    }
+
+    /// Folds numeric operands by the same rules as the VMs, with checked
+    /// integer arithmetic. Anything that would overflow or divide by zero is
+    /// left for the VM, so the error surfaces at runtime (or wraps, if the VM
+    /// is configured to). So are floats that come out infinite or NaN, which
+    /// have no literal to fold into.
+    fn fold_arithmetic(
+        l: Expr,
+        r: Expr,
+        op: ArithmeticOp,
+        rebuild: fn(Box<Expr>, Box<Expr>) -> Expr,
+    ) -> Expr {
+        if let (Expr::Literal(x), Expr::Literal(y)) = (&l, &r) {
+            match Overflow::Checked.numbers(op, x, y) {
+                Some(Ok(Value::Float(x))) if !x.0.is_finite() => {}
+                Some(Ok(value)) => return Expr::Literal(value),
+                _ => {}
+            }
         }
+        rebuild(Box::new(l), Box::new(r))
+    }
+
+    fn fold_unary(
+        x: Expr,
+        op: fn(Overflow, &Value) -> Option<Result<Value, ArithmeticError>>,
+        rebuild: fn(Box<Expr>) -> Expr,
+    ) -> Expr {
+        if let Expr::Literal(value) = &x {
+            if let Some(Ok(value)) = op(Overflow::Checked, value) {
+                return Expr::Literal(value);
+            }
+        }
+        rebuild(Box::new(x))
+    }
+
+    fn fold_comparison(
+        l: &Expr,
+        r: &Expr,
+        cmp: fn(Ordering) -> bool,
+        rebuild: fn(Box<Expr>, Box<Expr>) -> Expr,
+    ) -> Expr {
+        let (l, r) = (Self::optimize_expr(l), Self::optimize_expr(r));
+        match (constant(&l), constant(&r)) {
+            (Some(x), Some(y)) => Expr::Literal(cmp(x.compare(&y)).into()),
+            _ => rebuild(Box::new(l), Box::new(r)),
+        }
+    }
+}
+
+/// The value of `expr` if it's a literal all the way down, so comparing it
+/// at compile time gives the same answer as at runtime. Maps never are: the
+/// order of their entries and which duplicate key wins are only settled at
+/// runtime.
+fn constant(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Array(items) => items
+            .iter()
+            .map(constant)
+            .collect::<Option<_>>()
+            .map(Value::array),
+        Expr::Literal(value) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// Whether a constant `expr` is truthy, or `None` if that depends on what it
+/// evaluates to.
+fn truthiness(expr: &Expr) -> Option<bool> {
+    match expr {
+        Expr::Map(entries) if entries.is_empty() => Some(false),
+        Expr::Map(_) => None,
+        expr => constant(expr).map(|value| value.is_truthy()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Optimizer;
+    use crate::{parser::Parser, printer::Printer, tokenizer::Tokenizer};
+    use insta::assert_snapshot as test;
+
+    fn optimize(program: &str) -> String {
+        let tokens = Tokenizer::default().tokenize(program).unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        Printer::new(&Optimizer::optimize(&stmts)).to_string()
+    }
+
+    #[test]
+    fn folds_constants() {
+        test!(optimize(
+            "print 1 + 2 * 3; print -(4 - 6); print !(1 < 2); print \"a\" + \"b\";"
+        ));
+    }
+
+    #[test]
+    fn keeps_expressions_that_trap() {
+        test!(optimize(
+            "print 1 / 0; print 9223372036854775807 + 1; print -(0 - 9223372036854775807 - 1);"
+        ));
+    }
+
+    #[test]
+    fn folds_short_circuits() {
+        test!(optimize(
+            "print true && x; print 0 && f(); print \"\" || y; print [1] || f(); print x || true;"
+        ));
+    }
+
+    #[test]
+    fn collapses_dead_branches() {
+        test!(optimize(
+            "if 1 > 2 { print 1; } else { print 2; }
+             if false { print 1; } elif !false { print 2; } else { print 3; }
+             if false { print 1; }
+             if x { print 1; } elif y { if z { print 2; } } else { print 3; }"
+        ));
+    }
+
+    #[test]
+    fn folds_loops() {
+        test!(optimize(
+            "for (let i = 1 + 1; 2 < 1; i++) { print i; }
+             for (x = 0; x < 2 * 3; x--) { if x { continue; } }
+             for (;;) { break; }"
+        ));
+    }
+
+    #[test]
+    fn folds_modulo() {
+        test!(optimize(
+            "print 7 % 3; print -7 % 3; print 7 % -3; print 1 % 0; x %= 2 * 3; x -= 1 + 1;"
+        ));
+    }
+
+    #[test]
+    fn folds_inside_arrays() {
+        test!(optimize(
+            "print [1 + 1, x][0 * 1]; xs[2 - 1] += 3 * 2; print [1, [2]] == [1, [2]];"
+        ));
+    }
+
+    #[test]
+    fn keeps_maps() {
+        test!(optimize(
+            "print {1 + 1: \"a\" + \"b\"}; print {} || x; print {1: 2, 1: 3} == {1: 3};"
+        ));
+    }
+
+    #[test]
+    fn folds_floats() {
+        test!(optimize(
+            "print 1 + 0.5; print 2.0 * 3; print -1.5; print 1 == 1.0; print 2 < 2.5;
+             print 1.0 / 0; print 0.0 / 0.0; print 0.0 && x;"
+        ));
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn folds_big_integers() {
+        test!(optimize(
+            "print 99999999999999999999 * 2; print 9223372036854775808 - 1;
+             print -9223372036854775808; print 9223372036854775807 + 1;"
+        ));
+    }
+
+    #[test]
+    fn folds_interpolation() {
+        test!(optimize(
+            r#"print "a ${1 + 2} ${"b"} ${[1.5]}\n"; print "${x} and ${2 * 3}";"#
+        ));
+    }
+
+    #[test]
+    fn keeps_comparisons_of_variables() {
+        test!(optimize("print x < 1; print !y; print -z == [x];"));
     }
 }
@@ -0,0 +1,535 @@
+use crate::{
+    arithmetic::ArithmeticOp,
+    error::ParseError,
+    expr::Expr,
+    stmt::Stmt,
+    tokenizer::{Keyword, StringPart, Token, TokenType},
+    value::Value,
+};
+
+#[derive(Default, Debug, Clone)]
+pub struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, index: 0 }
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut stmts = vec![];
+        while !self.at_end() {
+            stmts.push(self.statement()?);
+        }
+        Ok(stmts)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        let stmt = match self.peek_type() {
+            Some(TokenType::Keyword(Keyword::Let)) => {
+                self.advance();
+                let name = self.identifier("variable name")?;
+                self.expect(TokenType::Equal, "'='")?;
+                Stmt::Assign(name, self.expression(0)?)
+            }
+            Some(TokenType::Keyword(Keyword::Fn))
+                if matches!(self.peek_nth_type(1), Some(TokenType::Identifier(_))) =>
+            {
+                return self.function()
+            }
+            Some(TokenType::Keyword(Keyword::If)) => return self.if_stmt(),
+            Some(TokenType::Keyword(Keyword::While)) => {
+                self.advance();
+                let cond = self.expression(0)?;
+                return Ok(Stmt::While(cond, self.block()?));
+            }
+            Some(TokenType::Keyword(Keyword::For)) => return self.for_stmt(),
+            Some(TokenType::Keyword(Keyword::Break)) => {
+                self.advance();
+                Stmt::Break
+            }
+            Some(TokenType::Keyword(Keyword::Continue)) => {
+                self.advance();
+                Stmt::Continue
+            }
+            Some(TokenType::LeftSquiggly) => return Ok(Stmt::Block(self.block()?)),
+            Some(TokenType::Keyword(Keyword::Print)) => {
+                self.advance();
+                Stmt::Print(self.expression(0)?)
+            }
+            Some(TokenType::Keyword(Keyword::Return)) => {
+                self.advance();
+                if self.ends_statement() {
+                    Stmt::Return(Expr::Literal(Value::Null))
+                } else {
+                    Stmt::Return(self.expression(0)?)
+                }
+            }
+            Some(TokenType::Keyword(Keyword::Exit)) => {
+                self.advance();
+                Stmt::Exit(self.expression(0)?)
+            }
+            _ => self.simple_statement()?,
+        };
+        self.end_statement()?;
+        Ok(stmt)
+    }
+
+    /// Parses a bare expression, such as an assignment, without the trailing
+    /// `;`.
+    fn simple_statement(&mut self) -> Result<Stmt, ParseError> {
+        Ok(Stmt::Expr(self.expression(0)?))
+    }
+
+    fn function(&mut self) -> Result<Stmt, ParseError> {
+        self.advance();
+        let name = self.identifier("function name")?;
+        let params = self.parameters()?;
+        Ok(Stmt::Func(name, params, self.block()?))
+    }
+
+    fn parameters(&mut self) -> Result<Vec<String>, ParseError> {
+        self.expect(TokenType::LeftParen, "'('")?;
+        let mut params = vec![];
+        while !self.check(&TokenType::RightParen) {
+            params.push(self.identifier("parameter name")?);
+            if !self.consume(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RightParen, "')'")?;
+        Ok(params)
+    }
+
+    fn if_stmt(&mut self) -> Result<Stmt, ParseError> {
+        self.advance();
+        let cond = self.expression(0)?;
+        let body = self.block()?;
+        let otherwise = match self.peek_type() {
+            // `elif` starts another `if`, which becomes the else branch.
+            Some(TokenType::Keyword(Keyword::ElseIf)) => Some(vec![self.if_stmt()?]),
+            Some(TokenType::Keyword(Keyword::Else)) => {
+                self.advance();
+                Some(self.block()?)
+            }
+            _ => None,
+        };
+        Ok(Stmt::If(cond, body, otherwise))
+    }
+
+    /// `for (init; cond; step) { body }`, where every part but the body may
+    /// be left out.
+    fn for_stmt(&mut self) -> Result<Stmt, ParseError> {
+        self.advance();
+        self.expect(TokenType::LeftParen, "'('")?;
+        let init = if self.consume(&TokenType::Semicolon) {
+            None
+        } else {
+            Some(Box::new(self.statement()?))
+        };
+        let cond = if self.check(&TokenType::Semicolon) {
+            Expr::Literal(Value::Bool(true))
+        } else {
+            self.expression(0)?
+        };
+        self.expect(TokenType::Semicolon, "';'")?;
+        let step = if self.check(&TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression(0)?)
+        };
+        self.expect(TokenType::RightParen, "')'")?;
+        Ok(Stmt::For(init, cond, step, self.block()?))
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        self.expect(TokenType::LeftSquiggly, "'{'")?;
+        let mut stmts = vec![];
+        while !self.check(&TokenType::RightSquiggly) {
+            if self.at_end() {
+                return Err(self.unexpected("'}'"));
+            }
+            stmts.push(self.statement()?);
+        }
+        self.advance();
+        Ok(stmts)
+    }
+
+    /// Precedence climbing: parses a unary operand, then folds in every binary
+    /// operator that binds at least as tightly as `min_prec`.
+    fn expression(&mut self, min_prec: u8) -> Result<Expr, ParseError> {
+        let mut lhs = self.unary()?;
+
+        while let Some(token) = self.tokens.get(self.index).cloned() {
+            let Some((prec, right_assoc)) = Self::binary_precedence(&token.token) else {
+                break;
+            };
+            if prec < min_prec {
+                break;
+            }
+            self.advance();
+            let next_prec = if right_assoc { prec } else { prec + 1 };
+            let rhs = Box::new(self.expression(next_prec)?);
+            let lhs_box = Box::new(lhs);
+            lhs = match token.token {
+                TokenType::Equal
+                | TokenType::AddAssign
+                | TokenType::SubAssign
+                | TokenType::MulAssign
+                | TokenType::DivAssign
+                | TokenType::ModAssign
+                    if !lhs_box.is_place() =>
+                {
+                    return Err(ParseError::InvalidAssignmentTarget { loc: token.loc })
+                }
+                TokenType::Equal => Expr::Assign(lhs_box, rhs),
+                TokenType::AddAssign => Expr::CompoundAssign(ArithmeticOp::Add, lhs_box, rhs),
+                TokenType::SubAssign => Expr::CompoundAssign(ArithmeticOp::Sub, lhs_box, rhs),
+                TokenType::MulAssign => Expr::CompoundAssign(ArithmeticOp::Mul, lhs_box, rhs),
+                TokenType::DivAssign => Expr::CompoundAssign(ArithmeticOp::Div, lhs_box, rhs),
+                TokenType::ModAssign => Expr::CompoundAssign(ArithmeticOp::Mod, lhs_box, rhs),
+                TokenType::Or => Expr::Or(lhs_box, rhs),
+                TokenType::And => Expr::And(lhs_box, rhs),
+                TokenType::EqualEqual => Expr::EqualEqual(lhs_box, rhs),
+                TokenType::NotEqual => Expr::NotEqual(lhs_box, rhs),
+                TokenType::LeftAngle => Expr::LessThan(lhs_box, rhs),
+                TokenType::LessThanEqual => Expr::LessThanEqual(lhs_box, rhs),
+                TokenType::RightAngle => Expr::GreaterThan(lhs_box, rhs),
+                TokenType::GreaterThanEqual => Expr::GreaterThanEqual(lhs_box, rhs),
+                TokenType::Plus => Expr::Add(lhs_box, rhs),
+                TokenType::Minus => Expr::Sub(lhs_box, rhs),
+                TokenType::Star => Expr::Mul(lhs_box, rhs),
+                TokenType::Backslash => Expr::Div(lhs_box, rhs),
+                TokenType::Percent => Expr::Mod(lhs_box, rhs),
+                _ => unreachable!("{:?} has no binary precedence", token.token),
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    /// Returns the precedence of a binary operator and whether it is right
+    /// associative.
+    fn binary_precedence(token: &TokenType) -> Option<(u8, bool)> {
+        match token {
+            TokenType::Equal
+            | TokenType::AddAssign
+            | TokenType::SubAssign
+            | TokenType::MulAssign
+            | TokenType::DivAssign
+            | TokenType::ModAssign => Some((1, true)),
+            TokenType::Or => Some((2, false)),
+            TokenType::And => Some((3, false)),
+            TokenType::EqualEqual | TokenType::NotEqual => Some((4, false)),
+            TokenType::LeftAngle
+            | TokenType::LessThanEqual
+            | TokenType::RightAngle
+            | TokenType::GreaterThanEqual => Some((5, false)),
+            TokenType::Plus | TokenType::Minus => Some((6, false)),
+            TokenType::Star | TokenType::Backslash | TokenType::Percent => Some((7, false)),
+            _ => None,
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek_type() {
+            Some(TokenType::Bang) => {
+                self.advance();
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(TokenType::Minus) => {
+                self.advance();
+                Ok(Expr::UnaryMinus(Box::new(self.unary()?)))
+            }
+            Some(TokenType::Plus) => {
+                self.advance();
+                Ok(Expr::UnaryPlus(Box::new(self.unary()?)))
+            }
+            _ => self.call(),
+        }
+    }
+
+    /// A primary expression followed by any number of `(args)` and `[index]`,
+    /// so the result of a call can be called or indexed in turn, and then an
+    /// optional `++` or `--`, which evaluates to the value from before.
+    fn call(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.consume(&TokenType::LeftParen) {
+                let args = self.arguments(TokenType::RightParen, "')'")?;
+                expr = Expr::Call(Box::new(expr), args);
+            } else if self.consume(&TokenType::LeftSquare) {
+                let index = self.expression(0)?;
+                self.expect(TokenType::RightSquare, "']'")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                break;
+            }
+        }
+        let op = match self.peek_type() {
+            Some(TokenType::Increment) => ArithmeticOp::Add,
+            Some(TokenType::Decrement) => ArithmeticOp::Sub,
+            _ => return Ok(expr),
+        };
+        let loc = self.tokens[self.index].loc;
+        self.advance();
+        if !expr.is_place() {
+            return Err(ParseError::InvalidAssignmentTarget { loc });
+        }
+        Ok(Expr::Postfix(op, Box::new(expr)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let Some(token) = self.tokens.get(self.index).cloned() else {
+            return Err(self.unexpected("an expression"));
+        };
+        self.advance();
+
+        match token.token {
+            TokenType::Number(n) => Ok(Expr::Literal(Value::Num(n))),
+            #[cfg(feature = "bigint")]
+            TokenType::BigInt(n) => Ok(Expr::Literal(Value::BigInt(n))),
+            TokenType::Float(x) => Ok(Expr::Literal(Value::Float(x))),
+            TokenType::String(s) => Ok(Expr::Literal(Value::String(s))),
+            TokenType::Interpolation(parts) => Ok(Expr::Interpolation(
+                parts
+                    .into_iter()
+                    .map(|part| match part {
+                        StringPart::Text(s) => Ok(Expr::Literal(Value::String(s))),
+                        StringPart::Code(tokens) => Parser::new(tokens).interpolated(),
+                    })
+                    .collect::<Result<_, _>>()?,
+            )),
+            TokenType::True => Ok(Expr::Literal(Value::Bool(true))),
+            TokenType::False => Ok(Expr::Literal(Value::Bool(false))),
+            TokenType::Nil => Ok(Expr::Literal(Value::Null)),
+            TokenType::Identifier(name) => Ok(Expr::Var(name)),
+            TokenType::Keyword(Keyword::Fn) => {
+                let params = self.parameters()?;
+                Ok(Expr::Lambda(params, self.block()?))
+            }
+            TokenType::LeftParen => {
+                let expr = self.expression(0)?;
+                self.expect(TokenType::RightParen, "')'")?;
+                Ok(expr)
+            }
+            TokenType::LeftSquare => {
+                let items = self.arguments(TokenType::RightSquare, "']'")?;
+                Ok(Expr::Array(items))
+            }
+            TokenType::LeftSquiggly => Ok(Expr::Map(self.entries()?)),
+            found => Err(ParseError::UnexpectedToken {
+                expected: "an expression".to_string(),
+                found,
+                loc: token.loc,
+            }),
+        }
+    }
+
+    /// Parses the tokens of a `${expr}` in a string, which must be a single
+    /// expression.
+    fn interpolated(mut self) -> Result<Expr, ParseError> {
+        let expr = self.expression(0)?;
+        if !self.at_end() {
+            return Err(self.unexpected("'}'"));
+        }
+        Ok(expr)
+    }
+
+    /// Parses a comma separated list of expressions up to and including
+    /// `close`, allowing a trailing comma.
+    fn arguments(&mut self, close: TokenType, expected: &str) -> Result<Vec<Expr>, ParseError> {
+        let mut args = vec![];
+        while !self.check(&close) {
+            args.push(self.expression(0)?);
+            if !self.consume(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(close, expected)?;
+        Ok(args)
+    }
+
+    /// Parses the `key: value` pairs of a map literal up to and including the
+    /// closing `}`, allowing a trailing comma.
+    fn entries(&mut self) -> Result<Vec<(Expr, Expr)>, ParseError> {
+        let mut entries = vec![];
+        while !self.check(&TokenType::RightSquiggly) {
+            let key = self.expression(0)?;
+            self.expect(TokenType::Colon, "':'")?;
+            entries.push((key, self.expression(0)?));
+            if !self.consume(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RightSquiggly, "'}'")?;
+        Ok(entries)
+    }
+
+    fn identifier(&mut self, expected: &str) -> Result<String, ParseError> {
+        match self.peek_type() {
+            Some(TokenType::Identifier(name)) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    fn ends_statement(&self) -> bool {
+        matches!(
+            self.peek_type(),
+            None | Some(TokenType::Semicolon | TokenType::RightSquiggly)
+        )
+    }
+
+    /// Statements are terminated by `;`, which may be left off before a `}`
+    /// or at the end of the program.
+    fn end_statement(&mut self) -> Result<(), ParseError> {
+        if self.consume(&TokenType::Semicolon) || self.ends_statement() {
+            Ok(())
+        } else {
+            Err(self.unexpected("';'"))
+        }
+    }
+
+    fn expect(&mut self, token: TokenType, expected: &str) -> Result<(), ParseError> {
+        if self.consume(&token) {
+            Ok(())
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    fn consume(&mut self, token: &TokenType) -> bool {
+        if self.check(token) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check(&self, token: &TokenType) -> bool {
+        self.peek_type() == Some(token)
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.tokens.get(self.index) {
+            Some(token) => ParseError::UnexpectedToken {
+                expected: expected.to_string(),
+                found: token.token.clone(),
+                loc: token.loc,
+            },
+            None => ParseError::UnexpectedEof {
+                expected: expected.to_string(),
+                loc: self.tokens.last().map(|t| t.loc).unwrap_or_default(),
+            },
+        }
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+    }
+
+    fn at_end(&self) -> bool {
+        self.index >= self.tokens.len()
+    }
+
+    fn peek_type(&self) -> Option<&TokenType> {
+        self.peek_nth_type(0)
+    }
+
+    fn peek_nth_type(&self, n: usize) -> Option<&TokenType> {
+        self.tokens.get(self.index + n).map(|t| &t.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Parser;
+    use crate::{error::ParseError, tokenizer::Tokenizer};
+    use insta::assert_yaml_snapshot as test;
+
+    fn parse(program: &str) -> Result<Vec<crate::stmt::Stmt>, ParseError> {
+        let tokens = Tokenizer::default().tokenize(program).unwrap();
+        Parser::new(tokens).parse()
+    }
+
+    macro_rules! snapshot {
+        ($name:ident, $program:expr) => {
+            #[test]
+            fn $name() {
+                test!(parse($program).unwrap());
+            }
+        };
+    }
+
+    snapshot!(print, "print 1;");
+    snapshot!(precedence, "print 1 + 2 * 3 - 4 / 2;");
+    snapshot!(grouping, "print (1 + 2) * 3;");
+    snapshot!(comparison, "1 < 2 == 3 >= 4;");
+    snapshot!(logical, "true && false || !null;");
+    snapshot!(unary, "-1 + +2;");
+    snapshot!(array, "let xs = [1, \"two\", [3]];");
+    snapshot!(add_assign, "x += y += 1;");
+    snapshot!(compound_assign, "x -= 1; x *= 2 % 3; x /= y %= 4;");
+    snapshot!(assign, "x = x + 1;");
+    snapshot!(chained_assign, "x = y = 1;");
+    snapshot!(var_decl, "let x = 10;");
+    snapshot!(fn_decl, "fn incr(i) { i += 1 }");
+    snapshot!(interpolation, r#"print "a${x}b${"c" + f(1, {2: 3})}\n";"#);
+    snapshot!(fn_call, "incr(10, x);");
+    snapshot!(curried_call, "add(1)(2)();");
+    snapshot!(
+        map,
+        "let m = {\"a\": 1, 2: [3], x + 1: {},}; m[\"a\"] += m[2][0];"
+    );
+    snapshot!(index, "xs[0] = xs[i + 1][2]; f()[0]++; [1, 2][1] *= 3;");
+    snapshot!(
+        lambda,
+        "let twice = fn(f, x) { return f(f(x)); }; (fn() {})();"
+    );
+    snapshot!(if_stmt, "if (x < 10) { print(10); }");
+    snapshot!(
+        if_else,
+        "if x { print 1 } else { print 2 } if x { print 1; } elif y { print 2; } elif z {} else { print 3; }"
+    );
+    snapshot!(
+        while_loop,
+        "let i = 0; while (i < 10) { print(i); i += 1; }"
+    );
+    snapshot!(for_loop, "for (let i = 0; i < 10; i += 1) { print(i); }");
+    snapshot!(
+        for_loop_parts,
+        "for (;;) { break; } for (i = 0; i < 3;) { i++; continue; } for (let j = 9; j; j--) {}"
+    );
+    snapshot!(block, "{ let x = 1; { print x } }");
+    snapshot!(
+        return_exit,
+        "fn f() { return; } fn g() { return 1 } exit(0)"
+    );
+
+    #[test]
+    fn missing_semicolon() {
+        test!(parse("let x = 1 let y = 2;").unwrap_err().to_string());
+    }
+
+    #[test]
+    fn unexpected_eof() {
+        test!(parse("print (1 + ").unwrap_err().to_string());
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        test!(parse("1 += 2;").unwrap_err().to_string());
+    }
+
+    #[test]
+    fn bitwise_operator() {
+        test!(parse("print 1 | 2;").unwrap_err().to_string());
+    }
+}
@@ -1,6 +1,9 @@
 use std::fmt;
 
-use crate::stmt::Stmt;
+use crate::{
+    expr::Expr,
+    stmt::{for_init, Stmt},
+};
 
 pub struct Printer {
     body: Vec<Stmt>,
@@ -32,15 +35,34 @@ fn indent_stmt(stmt: &Stmt, indent: usize) -> String {
     }
     match stmt {
         Stmt::Block(stmts) => {
-            s.push('{');
+            s.push_str(&indent_block(stmts, indent));
             s.push('\n');
-            for stmt in stmts {
-                s.push_str(&indent_stmt(stmt, indent + 1));
-            }
-            for _ in 0..indent {
-                s.push('\t');
+        }
+        Stmt::If(cond, body, otherwise) => {
+            s.push_str(&format!("if {cond} "));
+            s.push_str(&indent_block(body, indent));
+            let mut otherwise = otherwise;
+            while let Some(stmts) = otherwise {
+                match stmts.as_slice() {
+                    [Stmt::If(cond, body, next)] => {
+                        s.push_str(&format!(" elif {cond} "));
+                        s.push_str(&indent_block(body, indent));
+                        otherwise = next;
+                    }
+                    _ => {
+                        s.push_str(" else ");
+                        s.push_str(&indent_block(stmts, indent));
+                        break;
+                    }
+                }
             }
-            s.push('}');
+            s.push('\n');
+        }
+        Stmt::For(init, cond, step, body) => {
+            let init = init.as_deref().map(for_init).unwrap_or_default();
+            let step = step.as_ref().map(Expr::to_string).unwrap_or_default();
+            s.push_str(&format!("for ({init}; {cond}; {step}) "));
+            s.push_str(&indent_block(body, indent));
             s.push('\n');
         }
         _ => {
@@ -50,3 +72,17 @@ fn indent_stmt(stmt: &Stmt, indent: usize) -> String {
     }
     s
 }
+
+/// Renders `{`, then `stmts` one level deeper than `indent`, then the closing
+/// `}` without a newline.
+fn indent_block(stmts: &[Stmt], indent: usize) -> String {
+    let mut s = String::from("{\n");
+    for stmt in stmts {
+        s.push_str(&indent_stmt(stmt, indent + 1));
+    }
+    for _ in 0..indent {
+        s.push('\t');
+    }
+    s.push('}');
+    s
+}
@@ -0,0 +1,303 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    bytecode::{self, disassemble, Bytecode, Compiler},
+    optimizer::Optimizer,
+    parser::Parser,
+    printer::Printer,
+    serializer::Serdes,
+    stmt::Stmt,
+    tokenizer::{Token, TokenType, Tokenizer},
+    value::Value,
+    vm::{ExecutionResult, VM},
+};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+const HELP: &str = "\
+:ast [code]     print the parsed code, or the session so far
+:bc <code>      print the bytecode the code compiles to
+:opt [code]     print the optimized code, or the optimized session so far
+:env            print the variables and functions defined so far
+:reset          forget every definition
+:load <file>    run a source file, a session saved by :save (.irs), or a
+                program compiled by `ir compile` (.irb) on its own
+:save <file>    save the statements run so far as a session
+:help           print this message
+:quit           leave the repl";
+
+/// An interactive session. Every line is fed through one incremental
+/// [`Tokenizer`], and input is buffered until its brackets balance and any
+/// block comment ends, so a definition can span multiple lines. The
+/// session's output, including what the program prints, goes to the
+/// [`VM`]'s writer.
+pub struct Repl<R: BufRead, W: Write> {
+    reader: R,
+    tokenizer: Tokenizer,
+    pending: Vec<Token>,
+    history: Vec<Stmt>,
+    vm: VM<W>,
+}
+
+impl<R: BufRead, W: Write> Repl<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            tokenizer: Tokenizer::default().with_open_comments(),
+            pending: vec![],
+            history: vec![],
+            vm: VM::new(writer),
+        }
+    }
+
+    /// Runs the session until the input ends, `:quit`, or the program exits.
+    pub fn run(&mut self) -> io::Result<ExecutionResult> {
+        loop {
+            let prompt = if !self.continuing() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+            self.vm.writer.write_all(prompt.as_bytes())?;
+            self.vm.writer.flush()?;
+
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(ExecutionResult::Completed(()));
+            }
+
+            if !self.continuing() {
+                if let Some(command) = line.trim().strip_prefix(':') {
+                    if command == "quit" {
+                        return Ok(ExecutionResult::Completed(()));
+                    }
+                    match self.meta_command(command) {
+                        Ok(ExecutionResult::Completed(())) => {}
+                        Ok(exited) => return Ok(exited),
+                        Err(e) => writeln!(self.vm.writer, "error: {e}")?,
+                    }
+                    continue;
+                }
+            }
+
+            match self.tokenizer.tokenize(&line) {
+                Ok(tokens) => self.pending.extend(tokens),
+                Err(e) => {
+                    self.pending.clear();
+                    writeln!(self.vm.writer, "error: {e}")?;
+                    continue;
+                }
+            }
+            if depth(&self.pending) > 0 || self.tokenizer.in_comment() {
+                continue;
+            }
+
+            let tokens = std::mem::take(&mut self.pending);
+            match self.eval(tokens) {
+                Ok(ExecutionResult::Completed(())) => {}
+                Ok(exited) => return Ok(exited),
+                Err(e) => writeln!(self.vm.writer, "error: {e}")?,
+            }
+        }
+    }
+
+    /// Runs the statements parsed from `tokens`, echoing the value of any
+    /// expression statement that isn't `null`.
+    fn eval(&mut self, tokens: Vec<Token>) -> Result<ExecutionResult, Box<dyn Error>> {
+        for stmt in Parser::new(tokens).parse()? {
+            let res = if let Stmt::Expr(expr) = &stmt {
+                match self.vm.eval_value(expr)? {
+                    ExecutionResult::Completed(Value::Null) => ExecutionResult::Completed(()),
+                    ExecutionResult::Completed(value) => {
+                        writeln!(self.vm.writer, "{value}")?;
+                        ExecutionResult::Completed(())
+                    }
+                    ExecutionResult::Exited(code) => ExecutionResult::Exited(code),
+                }
+            } else {
+                self.vm.eval(std::slice::from_ref(&stmt))?
+            };
+            self.history.push(stmt);
+            if let ExecutionResult::Exited(_) = res {
+                return Ok(res);
+            }
+        }
+        Ok(ExecutionResult::Completed(()))
+    }
+
+    fn meta_command(&mut self, command: &str) -> Result<ExecutionResult, Box<dyn Error>> {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "ast" => {
+                let stmts = self.parse_or_history(arg)?;
+                writeln!(self.vm.writer, "{}", Printer::new(&stmts))?;
+            }
+            "bc" => {
+                let bytecode = Compiler::default().compile(&self.parse_or_history(arg)?)?;
+                writeln!(self.vm.writer, "{}", disassemble(&bytecode))?;
+            }
+            "opt" => {
+                let stmts = Optimizer::optimize(&self.parse_or_history(arg)?);
+                writeln!(self.vm.writer, "{}", Printer::new(&stmts))?;
+            }
+            "env" => {
+                let mut globals: Vec<_> = self.vm.globals.iter().collect();
+                globals.sort_by_key(|(name, _)| *name);
+                let (functions, values): (Vec<_>, Vec<_>) = globals
+                    .into_iter()
+                    .partition(|(_, value)| matches!(value, Value::Closure(_)));
+                for (name, value) in values {
+                    writeln!(self.vm.writer, "{name} = {value}")?;
+                }
+                for (name, function) in functions {
+                    if let Value::Closure(closure) = function {
+                        writeln!(self.vm.writer, "fn {name}({})", closure.params.join(", "))?;
+                    }
+                }
+            }
+            "reset" => {
+                self.vm.reset();
+                self.tokenizer = Tokenizer::default().with_open_comments();
+                self.history.clear();
+            }
+            "load" => {
+                if arg.ends_with(".irb") {
+                    // Compiled programs run on the bytecode VM, so their
+                    // definitions don't join the session.
+                    let bytecode: Vec<Bytecode> = Serdes::deserialize(&fs::read(arg)?)?;
+                    return Ok(bytecode::VM::new(&mut self.vm.writer).eval(&bytecode)?);
+                }
+                let stmts = if arg.ends_with(".irs") {
+                    Serdes::deserialize(&fs::read(arg)?)?
+                } else {
+                    let tokens = Tokenizer::default().tokenize(&fs::read_to_string(arg)?)?;
+                    Parser::new(tokens).parse()?
+                };
+                let res = self.vm.eval(&stmts)?;
+                self.history.extend(stmts);
+                return Ok(res);
+            }
+            "save" => fs::write(arg, Serdes::serialize(&self.history)?)?,
+            "help" => writeln!(self.vm.writer, "{HELP}")?,
+            _ => return Err(format!("unknown command :{name}, try :help").into()),
+        }
+        Ok(ExecutionResult::Completed(()))
+    }
+
+    fn parse_or_history(&mut self, code: &str) -> Result<Vec<Stmt>, Box<dyn Error>> {
+        if code.is_empty() {
+            return Ok(self.history.clone());
+        }
+        let tokens = self.tokenizer.tokenize(code)?;
+        self.tokenizer.finish()?;
+        Ok(Parser::new(tokens).parse()?)
+    }
+
+    /// Whether the input so far is waiting for more lines.
+    fn continuing(&self) -> bool {
+        !self.pending.is_empty() || self.tokenizer.in_comment()
+    }
+}
+
+/// How many `{` and `(` (and `[`) are still waiting to be closed.
+fn depth(tokens: &[Token]) -> isize {
+    tokens.iter().fold(0, |depth, token| match token.token {
+        TokenType::LeftSquiggly | TokenType::LeftParen | TokenType::LeftSquare => depth + 1,
+        TokenType::RightSquiggly | TokenType::RightParen | TokenType::RightSquare => depth - 1,
+        _ => depth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Repl;
+    use insta::assert_snapshot as test;
+
+    fn session(input: &str) -> String {
+        let mut output = vec![];
+        Repl::new(input.as_bytes(), &mut output).run().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn echo() {
+        test!(session("1 + 2\n\"a\" + \"b\"\nlet x = 10;\nx\nx += 5\n"));
+    }
+
+    #[test]
+    fn print() {
+        test!(session(
+            "print 1 + 2;\nif true {\n  print [\"a\", null];\n}\n"
+        ));
+    }
+
+    #[test]
+    fn persistent_state() {
+        test!(session(
+            "let total = 1;\nfn double(n) { return n * 2; }\ntotal += 1\n:env\n"
+        ));
+    }
+
+    #[test]
+    fn multi_line() {
+        test!(session(
+            "fn add(a, b) {\n  return a + b;\n}\nlet sum = (\n  1 +\n  2\n);\nsum\n:env\n"
+        ));
+    }
+
+    #[test]
+    fn errors_keep_session_alive() {
+        test!(session("let x = ;\ny\nlet x = 1;\nx\n:nope\n"));
+    }
+
+    #[test]
+    fn lex_errors() {
+        test!(session("print \"abc;\nprint \"${1 + 1} ok\";\n"));
+    }
+
+    #[test]
+    fn block_comments() {
+        test!(session(
+            "/* a\n:env */ print 1;\nprint /* b\n c */ 2; /* d */\n:ast 3 /* e\n4\n"
+        ));
+    }
+
+    #[test]
+    fn exit() {
+        let mut output = vec![];
+        let res = Repl::new("print 1;\nexit 3;\nprint 2;\n".as_bytes(), &mut output).run();
+        test!(format!("{res:?}\n{}", String::from_utf8(output).unwrap()));
+    }
+
+    #[test]
+    fn meta_commands() {
+        test!(session(
+            ":ast 1 + 2 * x\n:opt 1 + 2 * 3\n:bc 1 + 2\nlet x = 2 + 2;\n:ast\n:opt\n:reset\n:env\nx\n"
+        ));
+    }
+
+    #[test]
+    fn print_functions() {
+        test!(session(
+            ":ast fn f() { return 1; } fn g(a, b) { return a; }\n"
+        ));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join("ir_repl_save_and_load.irs");
+        let path = path.display();
+        test!(session(&format!(
+            "let x = 41;\nx += 1\n:save {path}\n:reset\n:load {path}\nx\n"
+        )));
+    }
+}
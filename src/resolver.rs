@@ -0,0 +1,307 @@
+use std::collections::HashSet;
+
+#[cfg(test)]
+use arbitrary::Arbitrary;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ResolveError, expr::Expr, stmt::Stmt};
+
+/// Where a local variable lives: `depth` scopes out from the one it's used in,
+/// at position `index` of that scope.
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+#[derive(Debug, Default)]
+struct Scope {
+    /// The variables declared so far, in slot order. A second `let` of the
+    /// same name takes a new slot and shadows the first.
+    declared: Vec<String>,
+    /// The variables declared by statements of this scope that haven't been
+    /// reached yet.
+    pending: Vec<String>,
+}
+
+/// Binds every variable to the scope that declares it before the program
+/// runs. Locals become [`Expr::Local`]s that carry their [`Slot`]. Anything
+/// else is a global and stays an [`Expr::Var`] looked up by name, so functions
+/// can use globals that are defined after them.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    scopes: Vec<Scope>,
+    globals: HashSet<String>,
+    /// How many loops the code being resolved is nested in, within the
+    /// innermost function.
+    loops: usize,
+}
+
+impl Resolver {
+    /// Tells the resolver about globals that were defined before the code it
+    /// resolves, as happens between lines of the repl.
+    pub fn with_globals<'a>(mut self, names: impl IntoIterator<Item = &'a String>) -> Self {
+        self.globals.extend(names.into_iter().cloned());
+        self
+    }
+
+    pub fn resolve(mut self, stmts: &[Stmt]) -> Result<Vec<Stmt>, ResolveError> {
+        self.globals.extend(declarations(stmts));
+        self.stmts(stmts)
+    }
+
+    pub fn resolve_expr(mut self, expr: &Expr) -> Result<Expr, ResolveError> {
+        self.expr(expr)
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Result<Vec<Stmt>, ResolveError> {
+        stmts.iter().map(|stmt| self.stmt(stmt)).collect()
+    }
+
+    /// Resolves `stmts` in a new scope whose first slots hold `params`.
+    fn scope(&mut self, params: &[String], stmts: &[Stmt]) -> Result<Vec<Stmt>, ResolveError> {
+        let mut scope = Scope::default();
+        for param in params {
+            if scope.declared.contains(param) {
+                return Err(ResolveError::DuplicateParameter(param.to_string()));
+            }
+            scope.declared.push(param.to_string());
+        }
+        scope.pending = declarations(stmts).collect();
+
+        self.scopes.push(scope);
+        let stmts = self.stmts(stmts);
+        self.scopes.pop();
+        stmts
+    }
+
+    fn declare(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            if let Some(i) = scope.pending.iter().position(|pending| pending == name) {
+                scope.pending.remove(i);
+            }
+            scope.declared.push(name.to_string());
+        }
+    }
+
+    /// Using a name before an enclosing scope declares it is an error unless
+    /// there's a global of that name for it to mean instead.
+    fn variable(&self, name: &str) -> Result<Expr, ResolveError> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(index) = scope.declared.iter().rposition(|declared| declared == name) {
+                return Ok(Expr::Local(name.to_string(), Slot { depth, index }));
+            }
+        }
+        let pending = self
+            .scopes
+            .iter()
+            .any(|scope| scope.pending.iter().any(|pending| pending == name));
+        if pending && !self.globals.contains(name) {
+            return Err(ResolveError::UseBeforeDeclaration(name.to_string()));
+        }
+        Ok(Expr::Var(name.to_string()))
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<Stmt, ResolveError> {
+        Ok(match stmt {
+            Stmt::Exit(expr) => Stmt::Exit(self.expr(expr)?),
+            Stmt::Print(expr) => Stmt::Print(self.expr(expr)?),
+            Stmt::Expr(expr) => Stmt::Expr(self.expr(expr)?),
+            Stmt::Return(expr) => Stmt::Return(self.expr(expr)?),
+            Stmt::If(cond, body, otherwise) => Stmt::If(
+                self.expr(cond)?,
+                self.scope(&[], body)?,
+                otherwise
+                    .as_ref()
+                    .map(|otherwise| self.scope(&[], otherwise))
+                    .transpose()?,
+            ),
+            Stmt::While(cond, body) => {
+                let cond = self.expr(cond)?;
+                Stmt::While(cond, self.loop_body(body)?)
+            }
+            // The initializer gets a scope around the whole loop, and the
+            // body one of its own inside that.
+            Stmt::For(init, cond, step, body) => {
+                self.scopes.push(Scope::default());
+                let res = self.for_loop(init.as_deref(), cond, step.as_ref(), body);
+                self.scopes.pop();
+                res?
+            }
+            Stmt::Break => self.loop_control(Stmt::Break, "break")?,
+            Stmt::Continue => self.loop_control(Stmt::Continue, "continue")?,
+            Stmt::Block(stmts) => Stmt::Block(self.scope(&[], stmts)?),
+            Stmt::Assign(name, value) => {
+                // The value is resolved first, so `let x = x + 1;` reads the
+                // `x` of an enclosing scope.
+                let value = self.expr(value)?;
+                self.declare(name);
+                Stmt::Assign(name.to_string(), value)
+            }
+            Stmt::Func(name, params, body) => {
+                // Declared before its body so that it can call itself.
+                self.declare(name);
+                Stmt::Func(
+                    name.to_string(),
+                    params.to_vec(),
+                    self.function(params, body)?,
+                )
+            }
+        })
+    }
+
+    fn for_loop(
+        &mut self,
+        init: Option<&Stmt>,
+        cond: &Expr,
+        step: Option<&Expr>,
+        body: &[Stmt],
+    ) -> Result<Stmt, ResolveError> {
+        let init = init.map(|init| self.stmt(init)).transpose()?;
+        let cond = self.expr(cond)?;
+        let step = step.map(|step| self.expr(step)).transpose()?;
+        let body = self.loop_body(body)?;
+        Ok(Stmt::For(init.map(Box::new), cond, step, body))
+    }
+
+    fn loop_body(&mut self, body: &[Stmt]) -> Result<Vec<Stmt>, ResolveError> {
+        self.loops += 1;
+        let body = self.scope(&[], body);
+        self.loops -= 1;
+        body
+    }
+
+    fn loop_control(&self, stmt: Stmt, keyword: &str) -> Result<Stmt, ResolveError> {
+        if self.loops == 0 {
+            return Err(ResolveError::OutsideLoop(keyword.to_string()));
+        }
+        Ok(stmt)
+    }
+
+    /// Resolves a function body. Loops around the function don't count
+    /// inside it, so it can't `break` out of them.
+    fn function(&mut self, params: &[String], body: &[Stmt]) -> Result<Vec<Stmt>, ResolveError> {
+        let loops = std::mem::take(&mut self.loops);
+        let body = self.scope(params, body);
+        self.loops = loops;
+        body
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<Expr, ResolveError> {
+        Ok(match expr {
+            Expr::Literal(_) => expr.clone(),
+            Expr::Array(items) => Expr::Array(
+                items
+                    .iter()
+                    .map(|item| self.expr(item))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Map(entries) => Expr::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| Ok((self.expr(key)?, self.expr(value)?)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Interpolation(parts) => Expr::Interpolation(
+                parts
+                    .iter()
+                    .map(|part| self.expr(part))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Index(array, index) => Expr::Index(self.boxed(array)?, self.boxed(index)?),
+            Expr::Var(name) | Expr::Local(name, _) => self.variable(name)?,
+            Expr::UnaryPlus(x) => Expr::UnaryPlus(self.boxed(x)?),
+            Expr::UnaryMinus(x) => Expr::UnaryMinus(self.boxed(x)?),
+            Expr::Not(x) => Expr::Not(self.boxed(x)?),
+            Expr::Add(x, y) => Expr::Add(self.boxed(x)?, self.boxed(y)?),
+            Expr::CompoundAssign(op, x, y) => {
+                Expr::CompoundAssign(*op, self.boxed(x)?, self.boxed(y)?)
+            }
+            Expr::Postfix(op, x) => Expr::Postfix(*op, self.boxed(x)?),
+            Expr::Assign(x, y) => Expr::Assign(self.boxed(x)?, self.boxed(y)?),
+            Expr::Sub(x, y) => Expr::Sub(self.boxed(x)?, self.boxed(y)?),
+            Expr::Mul(x, y) => Expr::Mul(self.boxed(x)?, self.boxed(y)?),
+            Expr::Div(x, y) => Expr::Div(self.boxed(x)?, self.boxed(y)?),
+            Expr::Mod(x, y) => Expr::Mod(self.boxed(x)?, self.boxed(y)?),
+            Expr::NotEqual(x, y) => Expr::NotEqual(self.boxed(x)?, self.boxed(y)?),
+            Expr::EqualEqual(x, y) => Expr::EqualEqual(self.boxed(x)?, self.boxed(y)?),
+            Expr::LessThan(x, y) => Expr::LessThan(self.boxed(x)?, self.boxed(y)?),
+            Expr::LessThanEqual(x, y) => Expr::LessThanEqual(self.boxed(x)?, self.boxed(y)?),
+            Expr::GreaterThan(x, y) => Expr::GreaterThan(self.boxed(x)?, self.boxed(y)?),
+            Expr::GreaterThanEqual(x, y) => Expr::GreaterThanEqual(self.boxed(x)?, self.boxed(y)?),
+            Expr::And(x, y) => Expr::And(self.boxed(x)?, self.boxed(y)?),
+            Expr::Or(x, y) => Expr::Or(self.boxed(x)?, self.boxed(y)?),
+            Expr::Call(callee, args) => Expr::Call(
+                self.boxed(callee)?,
+                args.iter()
+                    .map(|arg| self.expr(arg))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Lambda(params, body) => {
+                Expr::Lambda(params.to_vec(), self.function(params, body)?)
+            }
+            Expr::FnBody(body) => Expr::FnBody(self.function(&[], body)?),
+        })
+    }
+
+    fn boxed(&mut self, expr: &Expr) -> Result<Box<Expr>, ResolveError> {
+        Ok(Box::new(self.expr(expr)?))
+    }
+}
+
+/// The names that `stmts` declare directly, in order.
+fn declarations(stmts: &[Stmt]) -> impl Iterator<Item = String> + '_ {
+    stmts.iter().filter_map(|stmt| match stmt {
+        Stmt::Assign(name, _) | Stmt::Func(name, ..) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resolver;
+    use crate::{parser::Parser, stmt::Stmt, tokenizer::Tokenizer};
+    use insta::assert_yaml_snapshot as test;
+
+    fn resolve(program: &str) -> Result<Vec<Stmt>, String> {
+        let tokens = Tokenizer::default().tokenize(program).unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        Resolver::default()
+            .resolve(&stmts)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn slots() {
+        test!(resolve(
+            "let g = 1;
+             fn f(a, b) { let c = a; { let a = c + g; b = a; } return fn() { return b; }; }"
+        ));
+    }
+
+    #[test]
+    fn shadowing_and_globals() {
+        test!(resolve(
+            "{ let x = 1; let x = x + 1; { let x = x * 2; print x; } }
+             fn later() { return y; } let y = 2;"
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let errors: Vec<_> = [
+            "fn f(a, b, a) {}",
+            "let f = fn(x, x) { return x; };",
+            "fn f() { print y; let y = 1; }",
+            "{ let f = fn() { return f; }; }",
+            "{ fn even(n) { return odd(n); } fn odd(n) { return even(n); } }",
+            "if true { break; }",
+            "while true { let f = fn() { continue; }; }",
+        ]
+        .into_iter()
+        .map(|program| resolve(program).unwrap_err())
+        .collect();
+        test!(errors);
+    }
+}
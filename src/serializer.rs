@@ -1,13 +1,39 @@
-use crate::stmt::Stmt;
+use serde::{de::DeserializeOwned, Serialize};
 
+/// Reads and writes programs as bytes: the bytecode that `ir compile` saves
+/// for `ir exec`, and the statements of a repl session.
 pub struct Serdes;
 
 impl Serdes {
-    pub fn serialize(stmts: Vec<Stmt>) -> Vec<u8> {
-        bincode::serialize(&stmts).unwrap()
+    pub fn serialize<T: Serialize + ?Sized>(program: &T) -> bincode::Result<Vec<u8>> {
+        bincode::serialize(program)
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Vec<Stmt> {
-        bincode::deserialize(bytes).unwrap()
+    pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+        bincode::deserialize(bytes)
+    }
+}
+
+#[cfg(all(test, feature = "bigint"))]
+mod tests {
+    use super::Serdes;
+    use crate::{
+        bytecode::{Bytecode, Compiler},
+        parser::Parser,
+        tokenizer::Tokenizer,
+    };
+
+    #[test]
+    fn big_integers() {
+        let tokens = Tokenizer::default()
+            .tokenize("print 99999999999999999999 - 1;")
+            .unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let bytecode = Compiler::default().compile(&stmts).unwrap();
+        let bytes = Serdes::serialize(&bytecode).unwrap();
+        assert_eq!(
+            Serdes::deserialize::<Vec<Bytecode>>(&bytes).unwrap(),
+            bytecode
+        );
     }
 }
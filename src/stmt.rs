@@ -13,12 +13,19 @@ pub enum Stmt {
     Exit(Expr),
     Print(Expr),
     Expr(Expr),
-    If(Expr, Vec<Stmt>),
+    /// `if cond { body } else { otherwise }`. An `elif` is an `else` branch
+    /// holding just another `If`.
+    If(Expr, Vec<Stmt>, Option<Vec<Stmt>>),
     Block(Vec<Stmt>),
     Assign(String, Expr),
     Func(String, Vec<String>, Vec<Stmt>),
     Return(Expr),
     While(Expr, Vec<Stmt>),
+    /// `for (init; cond; step) { body }`. `init` is scoped to the loop, and
+    /// `continue` skips to `step`.
+    For(Option<Box<Stmt>>, Expr, Option<Expr>, Vec<Stmt>),
+    Break,
+    Continue,
 }
 
 impl fmt::Display for Stmt {
@@ -27,14 +34,14 @@ impl fmt::Display for Stmt {
             Stmt::Exit(code) => f.write_fmt(format_args!("exit({})", code)),
             Stmt::Print(num) => f.write_fmt(format_args!("print({})", num)),
             Stmt::Expr(expr) => f.write_fmt(format_args!("{};", expr)),
-            Stmt::If(cond, body) => {
-                let mut s = String::new();
-                for stmt in body {
-                    s.push_str(&stmt.to_string());
-                    s.push('\n');
+            Stmt::If(cond, body, otherwise) => {
+                f.write_fmt(format_args!("if {cond} {{ {} }}", join(body)))?;
+                match otherwise.as_deref() {
+                    None => Ok(()),
+                    // The nested `if` prints the rest of `elif ...`.
+                    Some([elif @ Stmt::If(..)]) => f.write_fmt(format_args!(" el{elif}")),
+                    Some(otherwise) => f.write_fmt(format_args!(" else {{ {} }}", join(otherwise))),
                 }
-                s.pop();
-                f.write_fmt(format_args!("if {cond} {{ {s} }}"))
             }
             Stmt::Block(stmts) => {
                 let mut s = String::from("{ ");
@@ -48,14 +55,7 @@ impl fmt::Display for Stmt {
             }
             Stmt::Assign(name, expr) => f.write_fmt(format_args!("let {name} = {expr}")),
             Stmt::Func(name, args, body) => {
-                let mut s = format!("fn {name}(");
-                for arg in args {
-                    s.push_str(arg);
-                    s.push_str(", ");
-                }
-                s.pop();
-                s.pop();
-                s.push_str(") {\n");
+                let mut s = format!("fn {name}({}) {{\n", args.join(", "));
                 for stmt in body {
                     s.push('\t');
                     s.push_str(&stmt.to_string());
@@ -65,6 +65,16 @@ impl fmt::Display for Stmt {
                 f.write_str(&s)
             }
             Stmt::Return(expr) => f.write_fmt(format_args!("return {expr}")),
+            Stmt::For(init, cond, step, body) => {
+                let init = init.as_deref().map(for_init).unwrap_or_default();
+                let step = step.as_ref().map(Expr::to_string).unwrap_or_default();
+                f.write_fmt(format_args!(
+                    "for ({init}; {cond}; {step}) {{ {} }}",
+                    join(body)
+                ))
+            }
+            Stmt::Break => f.write_str("break"),
+            Stmt::Continue => f.write_str("continue"),
             Stmt::While(cond, body) => {
                 let mut s = format!("while {cond} {{\n");
                 for stmt in body {
@@ -78,3 +88,17 @@ impl fmt::Display for Stmt {
         }
     }
 }
+
+/// The initializer of a `for` loop, without the `;` an expression statement
+/// would print with.
+pub(crate) fn for_init(init: &Stmt) -> String {
+    match init {
+        Stmt::Expr(expr) => expr.to_string(),
+        init => init.to_string(),
+    }
+}
+
+fn join(stmts: &[Stmt]) -> String {
+    let stmts: Vec<_> = stmts.iter().map(Stmt::to_string).collect();
+    stmts.join("\n")
+}
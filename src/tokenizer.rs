@@ -1,15 +1,34 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, ops::Range};
 
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
 use serde::{Deserialize, Serialize};
 
+use crate::{error::LexError, value::Float};
+
+/// Turns source into [`Token`]s. It can be fed a program in pieces, as the
+/// repl does a line at a time, and locations carry on from one piece to the
+/// next.
 #[derive(Hash, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Tokenizer {
     line: usize,
     col: usize,
+    /// The index of the current character in `chars`.
     index: usize,
+    /// The byte offset of the current character in `program`.
+    offset: usize,
     program: String,
     tokens: Vec<Token>,
     chars: Vec<char>,
+    /// Whether to keep whitespace and comments, see [`Tokenizer::with_trivia`].
+    trivia: bool,
+    /// Trivia that the next token will lead with.
+    pending: Vec<Trivia>,
+    /// Whether input may end inside a block comment, see
+    /// [`Tokenizer::with_open_comments`].
+    open_comments: bool,
+    /// Where the block comment that the input ended inside starts.
+    open_comment: Option<SourceLocation>,
 }
 
 impl Default for Tokenizer {
@@ -18,96 +37,192 @@ impl Default for Tokenizer {
             line: 1,
             col: 1,
             index: 0,
+            offset: 0,
             program: String::new(),
             tokens: vec![],
             chars: vec![],
+            trivia: false,
+            pending: vec![],
+            open_comments: false,
+            open_comment: None,
         }
     }
 }
 
 impl Tokenizer {
-    pub fn tokenize(&mut self, program: &str) -> Vec<Token> {
-        self.program.push_str(program);
-        self.chars.extend(program.chars());
-        let mut new_tokens = vec![];
-        let chars: Vec<char> = self.program.chars().collect();
-
-        let dict = HashMap::from([
-            ('(', TokenType::LeftParen),
-            (')', TokenType::RightParen),
-            ('[', TokenType::LeftSquare),
-            (']', TokenType::RightSquare),
-            ('{', TokenType::LeftSquiggly),
-            ('}', TokenType::RightSquiggly),
-            (';', TokenType::Semicolon),
-            (',', TokenType::Comma),
-            (':', TokenType::Colon),
-        ]);
+    /// Keeps the whitespace and comments before each token in its
+    /// [`Token::leading`], so that the source can be put back together
+    /// exactly from the tokens and [`Tokenizer::trailing`].
+    pub fn with_trivia(mut self) -> Self {
+        self.trivia = true;
+        self
+    }
 
-        while self.index < chars.len() {
-            let c = chars[self.index];
+    /// Lets input end inside a block comment, as a line of the repl can.
+    /// Rather than failing, [`Tokenizer::tokenize`] stops at the comment and
+    /// lexes it again once the next piece of input is added. Call
+    /// [`Tokenizer::finish`] when no more is coming.
+    pub fn with_open_comments(mut self) -> Self {
+        self.open_comments = true;
+        self
+    }
 
-            if dict.contains_key(&c) {
-                new_tokens.push(Token {
-                    loc: self.loc(),
-                    token: dict.get(&c).unwrap().clone(),
-                });
-                self.index += 1;
-                continue;
-            }
+    /// Whether the input so far ends inside a block comment.
+    pub fn in_comment(&self) -> bool {
+        self.open_comment.is_some()
+    }
 
-            match c {
-                '0'..='9' => new_tokens.push(self.number()),
-                '"' => new_tokens.push(self.string()),
-                '+' => new_tokens.push(self.rel_op(TokenType::Plus, TokenType::AddAssign)),
-                '-' => new_tokens.push(self.rel_op(TokenType::Minus, TokenType::SubAssign)),
-                '*' => new_tokens.push(self.rel_op(TokenType::Star, TokenType::MulAssign)),
-                '/' => new_tokens.push(self.rel_op(TokenType::Backslash, TokenType::DivAssign)),
-                '%' => new_tokens.push(self.rel_op(TokenType::Percent, TokenType::ModAssign)),
-                '!' => new_tokens.push(self.rel_op(TokenType::Bang, TokenType::NotEqual)),
-                '=' => new_tokens.push(self.rel_op(TokenType::Equal, TokenType::EqualEqual)),
-                '<' => new_tokens.push(self.rel_op(TokenType::LeftAngle, TokenType::LessThanEqual)),
-                '>' => {
-                    new_tokens.push(self.rel_op(TokenType::RightAngle, TokenType::GreaterThanEqual))
+    /// Ends the input, failing if it ends inside a block comment. The
+    /// comment is dropped, so more input starts afresh.
+    pub fn finish(&mut self) -> Result<(), LexError> {
+        match self.open_comment.take() {
+            Some(loc) => {
+                while self.current().is_some() {
+                    self.advance();
                 }
-                '&' => new_tokens.push(self.binary_char('&', TokenType::Ampersand, TokenType::And)),
-                '|' => new_tokens.push(self.binary_char('|', TokenType::Pipe, TokenType::Or)),
-                '\n' => {
-                    self.line += 1;
-                    self.col = 1;
-                    self.index += 1;
+                self.pending.clear();
+                Err(LexError::UnterminatedComment { loc })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The trivia after the last token so far, which will lead the next
+    /// one if there's more input.
+    pub fn trailing(&self) -> &[Trivia] {
+        &self.pending
+    }
+
+    pub fn tokenize(&mut self, program: &str) -> Result<Vec<Token>, LexError> {
+        self.program.push_str(program);
+        self.chars.extend(program.chars());
+        let mut new_tokens = vec![];
+        self.open_comment = None;
+
+        while self.current().is_some() {
+            let (index, loc) = (self.index, self.loc());
+            match self.token() {
+                Ok(Some(token)) => new_tokens.push(token),
+                Ok(None) => {}
+                // A comment in an interpolation is still an error, as the
+                // string around it is unterminated too.
+                Err(LexError::UnterminatedComment { loc: comment })
+                    if self.open_comments && comment == loc =>
+                {
+                    // Back up, so the next piece of input lexes the comment
+                    // again from its start.
+                    self.index = index;
+                    (self.line, self.col, self.offset) = (loc.line, loc.col, loc.offset);
+                    self.open_comment = Some(loc);
+                    break;
                 }
-                ' ' | '\t' => {
-                    self.col = 1;
-                    self.index += 1;
+                Err(e) => {
+                    // Skip the rest of the input, so that the next call
+                    // starts with its own.
+                    while self.current().is_some() {
+                        self.advance();
+                    }
+                    self.pending.clear();
+                    return Err(e);
                 }
-                '_' | 'a'..='z' | 'A'..='Z' => new_tokens.push(self.ident_or_keyword()),
-                _ => {}
             }
         }
 
         self.tokens.extend(new_tokens.clone());
-        new_tokens
+        Ok(new_tokens)
     }
 
-    fn ident_or_keyword(&mut self) -> Token {
-        let mut s = String::new();
+    /// Lexes the token that starts at the current character, or skips the
+    /// trivia there and returns `None`.
+    fn token(&mut self) -> Result<Option<Token>, LexError> {
         let loc = self.loc();
+        let c = self.advance();
 
-        while self.index < self.chars.len() {
-            let c = self.chars[self.index];
-            match c {
-                '_' | 'a'..='z' | 'A'..='Z' => {
-                    s.push(c);
-                    self.col += 1;
-                    self.index += 1;
+        let trivia: Option<fn(String) -> Trivia> = match c {
+            ' ' | '\t' | '\r' | '\n' => {
+                while let Some(' ' | '\t' | '\r' | '\n') = self.current() {
+                    self.advance();
                 }
-                _ => {
-                    self.col += 1;
-                    self.index += 1;
-                    break;
+                Some(Trivia::Whitespace)
+            }
+            '/' if self.current() == Some('/') => {
+                while self.current().is_some_and(|c| c != '\n') {
+                    self.advance();
                 }
+                Some(Trivia::LineComment)
             }
+            '/' if self.current() == Some('*') => {
+                self.block_comment(loc)?;
+                Some(Trivia::BlockComment)
+            }
+            _ => None,
+        };
+        if let Some(trivia) = trivia {
+            if self.trivia {
+                let text = self.program[loc.offset..self.offset].to_string();
+                self.pending.push(trivia(text));
+            }
+            return Ok(None);
+        }
+        let leading = std::mem::take(&mut self.pending);
+
+        let token = match c {
+            '0'..='9' => self.number(c, loc)?,
+            '"' => self.string(loc)?,
+            '+' if self.current() == Some('+') => {
+                self.binary_char('+', TokenType::Plus, TokenType::Increment)
+            }
+            '-' if self.current() == Some('-') => {
+                self.binary_char('-', TokenType::Minus, TokenType::Decrement)
+            }
+            '+' => self.rel_op(TokenType::Plus, TokenType::AddAssign),
+            '-' => self.rel_op(TokenType::Minus, TokenType::SubAssign),
+            '*' => self.rel_op(TokenType::Star, TokenType::MulAssign),
+            '/' => self.rel_op(TokenType::Backslash, TokenType::DivAssign),
+            '%' => self.rel_op(TokenType::Percent, TokenType::ModAssign),
+            '!' => self.rel_op(TokenType::Bang, TokenType::NotEqual),
+            '=' => self.rel_op(TokenType::Equal, TokenType::EqualEqual),
+            '<' => self.rel_op(TokenType::LeftAngle, TokenType::LessThanEqual),
+            '>' => self.rel_op(TokenType::RightAngle, TokenType::GreaterThanEqual),
+            '&' => self.binary_char('&', TokenType::Ampersand, TokenType::And),
+            '|' => self.binary_char('|', TokenType::Pipe, TokenType::Or),
+            '_' | 'a'..='z' | 'A'..='Z' => self.ident_or_keyword(c),
+            c => punctuation(c).ok_or(LexError::UnexpectedChar { c, loc })?,
+        };
+        Ok(Some(Token {
+            loc,
+            len: self.offset - loc.offset,
+            token,
+            leading,
+        }))
+    }
+
+    /// Skips a `/* */` comment that starts at `loc`, whose `/` has been
+    /// consumed. They don't nest, so the first `*/` ends it.
+    fn block_comment(&mut self, loc: SourceLocation) -> Result<(), LexError> {
+        self.advance();
+        loop {
+            match self.current() {
+                None => return Err(LexError::UnterminatedComment { loc }),
+                Some('*') if self.peek() == Some('/') => {
+                    self.advance();
+                    self.advance();
+                    return Ok(());
+                }
+                Some(_) => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    /// An identifier or keyword that starts with `first`. After the first
+    /// character, identifiers can have digits too.
+    fn ident_or_keyword(&mut self, first: char) -> TokenType {
+        let mut s = String::from(first);
+        while let Some(c @ ('_' | 'a'..='z' | 'A'..='Z' | '0'..='9')) = self.current() {
+            s.push(c);
+            self.advance();
         }
 
         let keywords = HashMap::from([
@@ -118,122 +233,266 @@ impl Tokenizer {
             ("else", TokenType::Keyword(Keyword::Else)),
             ("while", TokenType::Keyword(Keyword::While)),
             ("for", TokenType::Keyword(Keyword::For)),
+            ("break", TokenType::Keyword(Keyword::Break)),
+            ("continue", TokenType::Keyword(Keyword::Continue)),
             ("print", TokenType::Keyword(Keyword::Print)),
+            ("return", TokenType::Keyword(Keyword::Return)),
+            ("exit", TokenType::Keyword(Keyword::Exit)),
+            ("true", TokenType::True),
+            ("false", TokenType::False),
+            ("null", TokenType::Nil),
         ]);
 
-        Token {
-            loc,
-            token: if keywords.contains_key(&s.as_str()) {
-                keywords.get(&s.as_str()).unwrap().clone()
-            } else {
-                TokenType::Identifier(s)
-            },
+        if keywords.contains_key(&s.as_str()) {
+            keywords.get(&s.as_str()).unwrap().clone()
+        } else {
+            TokenType::Identifier(s)
         }
     }
 
-    fn rel_op(&mut self, short_token: TokenType, long_token: TokenType) -> Token {
+    fn rel_op(&mut self, short_token: TokenType, long_token: TokenType) -> TokenType {
         self.binary_char('=', short_token, long_token)
     }
 
+    /// The first character of the token has been consumed. It's
+    /// `long_token` if the next one is `second_char`, and `short_token` if
+    /// not.
     fn binary_char(
         &mut self,
         second_char: char,
         short_token: TokenType,
         long_token: TokenType,
-    ) -> Token {
-        if self.peek() == Some(second_char) {
-            let token = Token {
-                loc: self.loc(),
-                token: long_token,
-            };
-            self.index += 2;
-            token
+    ) -> TokenType {
+        if self.current() == Some(second_char) {
+            self.advance();
+            long_token
         } else {
-            let token = Token {
-                loc: self.loc(),
-                token: short_token,
-            };
-            self.index += 1;
-            token
+            short_token
         }
     }
 
-    fn string(&mut self) -> Token {
+    /// A string literal, whose opening `"` at `start` has been consumed. A
+    /// `\` escapes the character after it, and `${expr}` splices in the value
+    /// of `expr`, which makes the token an [`TokenType::Interpolation`].
+    fn string(&mut self, start: SourceLocation) -> Result<TokenType, LexError> {
+        let unterminated = LexError::UnterminatedString { loc: start };
+        let mut parts = vec![];
         let mut s = String::new();
-        let loc = self.loc();
-        self.index += 1;
-
-        while self.index < self.chars.len() {
-            let c = self.chars[self.index];
+        loop {
+            let loc = self.loc();
+            let Some(c) = self.current() else {
+                return Err(unterminated);
+            };
+            self.advance();
             match c {
-                '"' => {
-                    self.col += 1;
-                    self.index += 1;
-                    break;
+                '"' => break,
+                '\\' => {
+                    let Some(escape) = self.current() else {
+                        return Err(unterminated);
+                    };
+                    self.advance();
+                    s.push(match escape {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '0' => '\0',
+                        '"' | '\\' | '$' => escape,
+                        _ => return Err(LexError::InvalidEscape { escape, loc }),
+                    });
                 }
-                _ => {
-                    s.push(c);
-                    self.col += 1;
-                    self.index += 1;
+                '$' if self.current() == Some('{') => {
+                    self.advance();
+                    if !s.is_empty() {
+                        parts.push(StringPart::Text(std::mem::take(&mut s)));
+                    }
+                    parts.push(StringPart::Code(self.interpolation(start, loc)?));
                 }
+                _ => s.push(c),
             }
         }
 
-        Token {
-            loc,
-            token: TokenType::String(s),
+        if parts.is_empty() {
+            return Ok(TokenType::String(s));
         }
+        if !s.is_empty() {
+            parts.push(StringPart::Text(s));
+        }
+        Ok(TokenType::Interpolation(parts))
     }
 
-    fn number(&mut self) -> Token {
-        let mut s = String::new();
-        let loc = self.loc();
-        while self.index < self.chars.len() {
-            let c = self.chars[self.index];
-            match c {
-                '0'..='9' => {
-                    s.push(c);
-                    self.col += 1;
-                    self.index += 1;
+    /// The tokens of the `expr` in `${expr}`, which starts at `loc` in the
+    /// string that starts at `string`. The `}` that ends it is consumed.
+    fn interpolation(
+        &mut self,
+        string: SourceLocation,
+        loc: SourceLocation,
+    ) -> Result<Vec<Token>, LexError> {
+        let mut tokens = vec![];
+        // How many of the `{`s in `expr` are still open.
+        let mut depth = 0;
+        loop {
+            match self.current() {
+                None => return Err(LexError::UnterminatedString { loc: string }),
+                Some('}') if depth == 0 => {
+                    self.advance();
+                    // Trivia before the `}` is part of the string token, so
+                    // it mustn't lead the next one.
+                    self.pending.clear();
+                    break;
                 }
-                _ => break,
+                Some(_) => {}
+            }
+            if let Some(token) = self.token()? {
+                match token.token {
+                    TokenType::LeftSquiggly => depth += 1,
+                    TokenType::RightSquiggly => depth -= 1,
+                    _ => {}
+                }
+                tokens.push(token);
             }
         }
+        if tokens.is_empty() {
+            return Err(LexError::EmptyInterpolation { loc });
+        }
+        Ok(tokens)
+    }
 
-        Token {
-            loc,
-            token: TokenType::Number(s.parse().unwrap()),
+    /// An integer, or a float if it has a fraction or an exponent, as in
+    /// `1.5`, `2e10` or `3.0E-2`. Both need a digit after the `.` or `e`, so
+    /// `2e` is the integer `2` followed by the identifier `e`.
+    fn number(&mut self, first: char, loc: SourceLocation) -> Result<TokenType, LexError> {
+        let mut s = String::from(first);
+        self.digits(&mut s);
+
+        let mut float = false;
+        if self.current() == Some('.') && self.is_digit(self.index + 1) {
+            self.take(&mut s, 1);
+            self.digits(&mut s);
+            float = true;
+        }
+        if matches!(self.current(), Some('e' | 'E')) {
+            let sign = usize::from(matches!(self.peek(), Some('+' | '-')));
+            if self.is_digit(self.index + 1 + sign) {
+                self.take(&mut s, 1 + sign);
+                self.digits(&mut s);
+                float = true;
+            }
+        }
+
+        if float {
+            // Digits with a fraction or exponent always parse, though they
+            // may round to infinity.
+            Ok(TokenType::Float(Float(s.parse().unwrap())))
+        } else {
+            integer(s, loc)
         }
     }
 
-    fn peek(&self) -> Option<char> {
-        if self.index + 1 < self.chars.len() {
-            Some(self.chars[self.index + 1])
+    fn digits(&mut self, s: &mut String) {
+        while self.is_digit(self.index) {
+            self.take(s, 1);
+        }
+    }
+
+    fn is_digit(&self, index: usize) -> bool {
+        self.chars.get(index).is_some_and(char::is_ascii_digit)
+    }
+
+    /// Moves the next `n` characters onto the end of `s`.
+    fn take(&mut self, s: &mut String, n: usize) {
+        for _ in 0..n {
+            s.push(self.advance());
+        }
+    }
+
+    /// Consumes the current character, which must exist, and moves the
+    /// location past it.
+    fn advance(&mut self) -> char {
+        let c = self.chars[self.index];
+        self.index += 1;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
         } else {
-            None
+            self.col += 1;
         }
+        c
+    }
+
+    fn current(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index + 1).copied()
     }
 
     fn loc(&self) -> SourceLocation {
         SourceLocation {
             line: self.line,
             col: self.col,
+            offset: self.offset,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Default, Hash, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Token {
-    loc: SourceLocation,
-    token: TokenType,
+    pub loc: SourceLocation,
+    /// How many bytes of source the token takes up.
+    pub len: usize,
+    pub token: TokenType,
+    /// The whitespace and comments between the previous token and this one,
+    /// if the [`Tokenizer`] was asked to keep them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leading: Vec<Trivia>,
+}
+
+/// Source between tokens that doesn't change what the program means. Each
+/// holds its text exactly as written, delimiters and all.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trivia {
+    Whitespace(String),
+    /// A `//` comment, up to but not including the end of its line.
+    LineComment(String),
+    /// A `/* */` comment.
+    BlockComment(String),
+}
+
+impl Trivia {
+    pub fn text(&self) -> &str {
+        match self {
+            Trivia::Whitespace(text) | Trivia::LineComment(text) | Trivia::BlockComment(text) => {
+                text
+            }
+        }
+    }
+}
+
+impl Token {
+    /// Where the token is in the source given to the [`Tokenizer`], as byte
+    /// offsets.
+    pub fn span(&self) -> Range<usize> {
+        self.loc.offset..self.loc.offset + self.len
+    }
 }
 
 #[derive(
     Serialize, Deserialize, Default, Hash, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
 )]
+/// A position in the source. `line` and `col` count from 1, with `col` in
+/// characters, and `offset` is in bytes from the start.
 pub struct SourceLocation {
-    line: usize,
-    col: usize,
+    pub line: usize,
+    pub col: usize,
+    pub offset: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.line, self.col))
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -241,7 +500,13 @@ pub enum TokenType {
     #[default]
     Eof,
     Number(i64),
+    /// An integer literal too big for a `Number`.
+    #[cfg(feature = "bigint")]
+    BigInt(BigInt),
+    Float(Float),
     String(String),
+    /// A string literal with at least one `${expr}` in it.
+    Interpolation(Vec<StringPart>),
     Identifier(String),
     Plus,
     Minus,
@@ -251,6 +516,8 @@ pub enum TokenType {
     MulAssign,
     DivAssign,
     ModAssign,
+    Increment,
+    Decrement,
     Comma,
     LeftParen,
     RightParen,
@@ -280,6 +547,14 @@ pub enum TokenType {
     Keyword(Keyword),
 }
 
+/// A piece of an interpolated string literal.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StringPart {
+    Text(String),
+    /// The tokens of the `expr` in a `${expr}`.
+    Code(Vec<Token>),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Keyword {
     Let,
@@ -289,12 +564,49 @@ pub enum Keyword {
     ElseIf,
     Else,
     For,
+    Break,
+    Continue,
     Print,
+    Return,
+    Exit,
+}
+
+/// The tokens that are always a single character.
+fn punctuation(c: char) -> Option<TokenType> {
+    Some(match c {
+        '(' => TokenType::LeftParen,
+        ')' => TokenType::RightParen,
+        '[' => TokenType::LeftSquare,
+        ']' => TokenType::RightSquare,
+        '{' => TokenType::LeftSquiggly,
+        '}' => TokenType::RightSquiggly,
+        ';' => TokenType::Semicolon,
+        ',' => TokenType::Comma,
+        ':' => TokenType::Colon,
+        _ => return None,
+    })
+}
+
+#[cfg(not(feature = "bigint"))]
+fn integer(literal: String, loc: SourceLocation) -> Result<TokenType, LexError> {
+    match literal.parse() {
+        Ok(n) => Ok(TokenType::Number(n)),
+        Err(_) => Err(LexError::IntegerOverflow { literal, loc }),
+    }
+}
+
+#[cfg(feature = "bigint")]
+fn integer(literal: String, _: SourceLocation) -> Result<TokenType, LexError> {
+    match literal.parse() {
+        Ok(n) => Ok(TokenType::Number(n)),
+        Err(_) => Ok(TokenType::BigInt(literal.parse().unwrap())),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Tokenizer;
+    use super::{Tokenizer, Trivia};
+    use arbtest::arbtest;
     use insta::assert_yaml_snapshot as test;
 
     macro_rules! snapshot {
@@ -302,7 +614,7 @@ mod tests {
             #[test]
             fn $name() {
                 let mut tokenizer = Tokenizer::default();
-                let res = tokenizer.tokenize($program);
+                let res = tokenizer.tokenize($program).unwrap();
                 test!(res);
             }
         };
@@ -310,6 +622,7 @@ mod tests {
 
     snapshot!(print, "print 1;");
     snapshot!(number, " 10000");
+    snapshot!(float, "1.5 0.25e2 3E-2 4e+1 6e 7e-x");
     snapshot!(plus, "1 + 1");
     snapshot!(add_assign, "1 += 1");
     snapshot!(sub, "1 - 1");
@@ -319,8 +632,16 @@ mod tests {
     snapshot!(div, "1 / 1");
     snapshot!(div_assign, "1 /= 1");
     snapshot!(rem, "1 % 1");
+    snapshot!(increment, "i++ + ++i");
+    snapshot!(decrement, "i-- - -1");
+    snapshot!(break_continue, "break; continue;");
     snapshot!(mod_assign, "1 %= 1");
     snapshot!(string, "\"hello\" + \"world\"");
+    snapshot!(escapes, r#""a\"b\\c\nd\te\$f\0""#);
+    snapshot!(
+        interpolation,
+        r#""x = ${x + 1}, ${f("${y}")}${{}}!" "$ {}""#
+    );
     snapshot!(equal_equal, "2 ==  3");
     snapshot!(equal, "1 = 1");
     snapshot!(not_equal, "1 != 1");
@@ -345,6 +666,121 @@ mod tests {
     );
     snapshot!(fn_decl, "fn incr(i) { i += 1 }");
     snapshot!(fn_call, "incr(10);");
+    snapshot!(identifiers, "x1 _a2b x_3 9z");
+    snapshot!(no_spaces, "x+1-y");
+    snapshot!(comments, "1 // one\n/* two\n */ 2 / /**/ 3 // end");
+    snapshot!(lines, "let x = 1;\n  print \"é\" + x;\n\tx\r\n");
+
+    #[test]
+    fn errors() {
+        let errors: Vec<_> = [
+            "print \"abc;",
+            "\"abc\\",
+            "\"a\\qb\"",
+            "\"${}\"",
+            "\"${x\"",
+            "\"${\"a\" + \"}",
+            "let x = 1;\n  x @ 2;",
+            "print \"é\" # x;",
+            "x.y",
+            "1 /* 2",
+        ]
+        .into_iter()
+        .map(|program| {
+            Tokenizer::default()
+                .tokenize(program)
+                .unwrap_err()
+                .to_string()
+        })
+        .collect();
+        test!(errors);
+    }
+    #[cfg(not(feature = "bigint"))]
+    #[test]
+    fn integer_overflow() {
+        let err = Tokenizer::default()
+            .tokenize("1 + 99999999999999999999")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1:5: integer literal 99999999999999999999 is too large"
+        );
+    }
+
+    #[test]
+    fn trivia() {
+        let mut tokenizer = Tokenizer::default().with_trivia();
+        let tokens = tokenizer
+            .tokenize("/// Doubles.\nfn double(x) { return 2 * x; /* ok */ }\n")
+            .unwrap();
+        test!((
+            &tokens[..3],
+            &tokens[tokens.len() - 1],
+            tokenizer.trailing()
+        ));
+    }
+
+    #[test]
+    fn open_comments() {
+        let mut tokenizer = Tokenizer::default().with_open_comments().with_trivia();
+        let first = tokenizer.tokenize("1 /* a\n").unwrap();
+        assert!(tokenizer.in_comment());
+        let second = tokenizer.tokenize("b */ 2\n/* c").unwrap();
+        let err = tokenizer.finish().unwrap_err().to_string();
+        assert!(!tokenizer.in_comment());
+        test!((first, second, err));
+    }
+
+    /// Any input lexes or fails without panicking, and each token's
+    /// location agrees with its span in the source.
+    #[test]
+    fn spans() {
+        // Trivia before the `}` of an interpolation is inside the string.
+        for program in ["\"${x }\" y", "\"${x /* c */}\";", "\"${ {} // c\n}\"\n"] {
+            check_spans(program);
+        }
+        arbtest(|u| {
+            let alphabet: Vec<char> = "ab1_.eE+-*/%=!<>&|\"\\${}()[],:; \n\t\r\u{e9}@"
+                .chars()
+                .collect();
+            let len = u.arbitrary_len::<u8>()?;
+            let mut program = String::new();
+            for _ in 0..len {
+                program.push(if u.ratio(1, 10)? {
+                    u.arbitrary()?
+                } else {
+                    *u.choose(&alphabet)?
+                });
+            }
+            check_spans(&program);
+            Ok(())
+        });
+    }
+
+    fn check_spans(program: &str) {
+        let mut tokenizer = Tokenizer::default().with_trivia();
+        let Ok(tokens) = tokenizer.tokenize(program) else {
+            return;
+        };
+
+        // With the trivia, the tokens make up the whole source.
+        let mut source = String::new();
+        for token in &tokens {
+            source.extend(token.leading.iter().map(Trivia::text));
+            source.push_str(&program[token.span()]);
+        }
+        source.extend(tokenizer.trailing().iter().map(Trivia::text));
+        assert_eq!(source, program);
+
+        for token in tokens {
+            let before = &program[..token.loc.offset];
+            assert!(program.get(token.span()).is_some_and(|s| !s.is_empty()));
+            assert_eq!(token.loc.line, before.matches('\n').count() + 1);
+            let line = before.rsplit('\n').next().unwrap();
+            assert_eq!(token.loc.col, line.chars().count() + 1);
+        }
+    }
+
     snapshot!(if_stmt, "if (x < 10) { print(10); }");
     snapshot!(
         else_stmt,
@@ -1,53 +1,393 @@
-use std::fmt;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
 
 #[cfg(test)]
 use arbitrary::Arbitrary;
+#[cfg(feature = "bigint")]
+use num_bigint::{BigInt, Sign};
+#[cfg(feature = "bigint")]
+use num_traits::{FromPrimitive, ToPrimitive};
 use serde::{Deserialize, Serialize};
 
-use crate::expr::Expr;
+use crate::{
+    builtin::Builtin,
+    bytecode::{self, Function},
+    error::CollectionError,
+    vm::Closure,
+};
 
 #[cfg_attr(test, derive(Arbitrary))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Value {
     Bool(bool),
     Num(i64),
+    /// An integer that doesn't fit in a `Num`. It never holds one that does,
+    /// so each integer has a single representation and `Eq` holds for them.
+    #[cfg(feature = "bigint")]
+    BigInt(BigInt),
+    Float(Float),
     String(String),
-    Array(Vec<Expr>),
+    /// Arrays are shared: every copy of one sees what is pushed or assigned
+    /// through the others. An array never holds itself, even through other
+    /// collections, so printing and comparing one always ends.
+    Array(Rc<RefCell<Vec<Value>>>),
+    /// Shared like arrays, and likewise never inside itself. Keys that are
+    /// equal numbers are the same key, whether written as integers or floats.
+    /// Entries are ordered by key, so iterating and
+    /// printing a map doesn't depend on the order of insertion.
+    Map(Rc<RefCell<BTreeMap<Value, Value>>>),
+    Function(Rc<Function>),
+    /// A function of the bytecode VM that captured variables around it.
+    BytecodeClosure(Rc<bytecode::Closure>),
+    Closure(Rc<Closure>),
+    Builtin(Builtin),
     Null,
 }
 
+/// A float with a total order, so that [`Value`] can be `Ord` and used as a
+/// map key. It agrees with IEEE 754 comparisons except that every NaN is
+/// equal to every other and greater than any number.
+#[cfg_attr(test, derive(Arbitrary))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(transparent)]
+pub struct Float(pub f64);
+
+impl PartialEq for Float {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Float {}
+
+impl PartialOrd for Float {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Float {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .partial_cmp(&other.0)
+            .unwrap_or_else(|| self.0.is_nan().cmp(&other.0.is_nan()))
+    }
+}
+
+impl Hash for Float {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Values that are equal hash the same: both zeros, and every NaN.
+        match self.0 {
+            x if x.is_nan() => f64::NAN.to_bits().hash(state),
+            0.0 => 0.0f64.to_bits().hash(state),
+            x => x.to_bits().hash(state),
+        }
+    }
+}
+
+/// Always has a decimal point or exponent, so a float never prints like an
+/// integer: `1.0`, `0.5`, `1e100`, `nan`, `inf` and `-inf`.
+impl fmt::Display for Float {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            x if x.is_nan() => f.write_str("nan"),
+            x if x.is_infinite() && x > 0.0 => f.write_str("inf"),
+            x if x.is_infinite() => f.write_str("-inf"),
+            x => f.write_fmt(format_args!("{x:?}")),
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Num(n) => f.write_str(&n.to_string()),
-            Value::String(s) => f.write_fmt(format_args!("\"{}\"", &s)),
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => n.fmt(f),
+            Value::Float(x) => x.fmt(f),
+            Value::String(s) => f.write_fmt(format_args!("\"{}\"", escape(s))),
             Value::Array(arr) => {
-                let mut res = String::from("[");
-                for item in arr {
-                    res.push_str(&item.to_string());
-                    res.push_str(", ");
-                }
-                res.pop();
-                res.pop();
-                res.push(']');
-                f.write_str(&res)
+                let items: Vec<_> = arr.borrow().iter().map(Value::to_string).collect();
+                f.write_fmt(format_args!("[{}]", items.join(", ")))
+            }
+            Value::Map(entries) => {
+                let entries: Vec<_> = entries
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| format!("{key}: {value}"))
+                    .collect();
+                f.write_fmt(format_args!("{{{}}}", entries.join(", ")))
             }
             Value::Bool(b) => f.write_str(&b.to_string()),
+            Value::Function(function) => f.write_fmt(format_args!("<fn {}>", function.name)),
+            Value::BytecodeClosure(closure) => {
+                f.write_fmt(format_args!("<fn {}>", closure.function.name))
+            }
+            Value::Closure(closure) => f.write_fmt(format_args!("<fn {}>", closure.name)),
+            Value::Builtin(builtin) => f.write_fmt(format_args!("<fn {}>", builtin.name())),
             Value::Null => f.write_str("null"),
         }
     }
 }
 
 impl Value {
+    pub fn array(items: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn map(entries: BTreeMap<Value, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+
+    /// A `Num` if `n` fits in one, otherwise a `BigInt`.
+    #[cfg(feature = "bigint")]
+    pub fn integer(n: BigInt) -> Value {
+        match i64::try_from(&n) {
+            Ok(n) => Value::Num(n),
+            Err(_) => Value::BigInt(n),
+        }
+    }
+
     pub fn is_truthy(&self) -> bool {
         match self {
             Value::Bool(b) => *b,
             Value::Num(n) => *n != 0,
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => n.sign() != Sign::NoSign,
+            Value::Float(x) => x.0 != 0.0,
             Value::String(s) => !s.is_empty(),
-            Value::Array(vec) => !vec.is_empty(),
+            Value::Array(vec) => !vec.borrow().is_empty(),
+            Value::Map(entries) => !entries.borrow().is_empty(),
+            Value::Function(_)
+            | Value::BytecodeClosure(_)
+            | Value::Closure(_)
+            | Value::Builtin(_) => true,
             Value::Null => false,
         }
     }
+
+    /// How the value reads inside an interpolated string: a string as it is,
+    /// and anything else as it prints.
+    pub fn text(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        }
+    }
+
+    /// A number as a float, or `None` for anything else.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Num(n) => Some(*n as f64),
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => n.to_f64(),
+            Value::Float(x) => Some(x.0),
+            _ => None,
+        }
+    }
+
+    /// An integer of either size as a `BigInt`, or `None` for anything else.
+    #[cfg(feature = "bigint")]
+    pub fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Num(n) => Some(BigInt::from(*n)),
+            Value::BigInt(n) => Some(n.clone()),
+            _ => None,
+        }
+    }
+
+    /// How the language compares values. Integers and floats compare by
+    /// value, so `1 == 1.0`, and so do arrays of them and maps with them as
+    /// values. NaN is equal to itself,
+    /// as [`Float`] orders it, so `nan == nan` and NaN works as a map key.
+    /// Anything else compares as `Ord` does, which puts values of different
+    /// types in a fixed order.
+    pub fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            #[cfg(feature = "bigint")]
+            (Value::BigInt(_), Value::Num(_) | Value::BigInt(_))
+            | (Value::Num(_), Value::BigInt(_)) => self.as_bigint().cmp(&other.as_bigint()),
+            #[cfg(feature = "bigint")]
+            (Value::BigInt(_), Value::Float(y)) => Float(self.as_float().unwrap()).cmp(y),
+            #[cfg(feature = "bigint")]
+            (Value::Float(x), Value::BigInt(_)) => x.cmp(&Float(other.as_float().unwrap())),
+            (Value::Num(x), Value::Float(y)) => Float(*x as f64).cmp(y),
+            (Value::Float(x), Value::Num(y)) => x.cmp(&Float(*y as f64)),
+            (Value::Array(x), Value::Array(y)) => {
+                let (x, y) = (x.borrow(), y.borrow());
+                x.iter()
+                    .zip(y.iter())
+                    .map(|(x, y)| x.compare(y))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or_else(|| x.len().cmp(&y.len()))
+            }
+            // Keys are already normalized, so they compare as the map orders
+            // them.
+            (Value::Map(x), Value::Map(y)) => {
+                let (x, y) = (x.borrow(), y.borrow());
+                x.iter()
+                    .zip(y.iter())
+                    .map(|((xk, xv), (yk, yv))| xk.cmp(yk).then_with(|| xv.compare(yv)))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or_else(|| x.len().cmp(&y.len()))
+            }
+            _ => self.cmp(other),
+        }
+    }
+
+    /// `self[index]`, where `self` is an array or a map.
+    pub fn get_index(&self, index: &Value) -> Result<Value, CollectionError> {
+        if let Value::Map(entries) = self {
+            return entries
+                .borrow()
+                .get(&index.to_key()?)
+                .cloned()
+                .ok_or_else(|| CollectionError::MissingKey(index.clone()));
+        }
+        let items = self.items("[]")?.borrow();
+        let index = index.as_index()?;
+        items
+            .get(to_usize(index))
+            .cloned()
+            .ok_or(CollectionError::OutOfBounds {
+                index,
+                len: items.len(),
+            })
+    }
+
+    /// `self[index] = value`. Arrays only replace items, but maps insert the
+    /// key if it's new.
+    pub fn set_index(&self, index: &Value, value: Value) -> Result<(), CollectionError> {
+        if let Value::Map(entries) = self {
+            let key = index.to_key()?;
+            self.check_acyclic(&value)?;
+            entries.borrow_mut().insert(key, value);
+            return Ok(());
+        }
+        self.check_acyclic(&value)?;
+        let mut items = self.items("[]=")?.borrow_mut();
+        let index = index.as_index()?;
+        let len = items.len();
+        match items.get_mut(to_usize(index)) {
+            Some(item) => *item = value,
+            None => return Err(CollectionError::OutOfBounds { index, len }),
+        }
+        Ok(())
+    }
+
+    /// The items of an array, or an error naming `op` for anything else.
+    pub(crate) fn items(
+        &self,
+        op: &'static str,
+    ) -> Result<&Rc<RefCell<Vec<Value>>>, CollectionError> {
+        match self {
+            Value::Array(items) => Ok(items),
+            value => Err(CollectionError::Unsupported {
+                op,
+                value: value.clone(),
+            }),
+        }
+    }
+
+    /// The contents of a string, or an error naming `op` for anything else.
+    pub(crate) fn string(&self, op: &'static str) -> Result<&str, CollectionError> {
+        match self {
+            Value::String(s) => Ok(s),
+            value => Err(CollectionError::Unsupported {
+                op,
+                value: value.clone(),
+            }),
+        }
+    }
+
+    /// The entries of a map, or an error naming `op` for anything else.
+    pub(crate) fn entries(
+        &self,
+        op: &'static str,
+    ) -> Result<&Rc<RefCell<BTreeMap<Value, Value>>>, CollectionError> {
+        match self {
+            Value::Map(entries) => Ok(entries),
+            value => Err(CollectionError::Unsupported {
+                op,
+                value: value.clone(),
+            }),
+        }
+    }
+
+    /// Fails if `value` is this array or map or holds it somewhere inside.
+    /// Storing it here would make a cycle, which printing and comparing would
+    /// follow forever.
+    pub(crate) fn check_acyclic(&self, value: &Value) -> Result<(), CollectionError> {
+        let target = match self {
+            Value::Array(items) => Rc::as_ptr(items).cast(),
+            Value::Map(entries) => Rc::as_ptr(entries).cast(),
+            _ => return Ok(()),
+        };
+        if value.reaches(target, &mut HashSet::new()) {
+            return Err(CollectionError::Cycle);
+        }
+        Ok(())
+    }
+
+    /// Whether the collection at `target` is this value or inside it. `seen`
+    /// keeps a collection that is shared from being searched twice.
+    fn reaches(&self, target: *const (), seen: &mut HashSet<*const ()>) -> bool {
+        let ptr = match self {
+            Value::Array(items) => Rc::as_ptr(items).cast(),
+            Value::Map(entries) => Rc::as_ptr(entries).cast(),
+            _ => return false,
+        };
+        if ptr == target {
+            return true;
+        }
+        if !seen.insert(ptr) {
+            return false;
+        }
+        match self {
+            Value::Array(items) => items.borrow().iter().any(|item| item.reaches(target, seen)),
+            // Keys are never collections.
+            Value::Map(entries) => entries
+                .borrow()
+                .values()
+                .any(|value| value.reaches(target, seen)),
+            _ => false,
+        }
+    }
+
+    /// The value as a map key. A float equal to an integer becomes that
+    /// integer, so `m[1.0]` is `m[1]` just as `1.0 == 1`. Arrays and maps
+    /// can't be keys: changing one through another reference would leave it
+    /// out of order in the map.
+    pub(crate) fn to_key(&self) -> Result<Value, CollectionError> {
+        match *self {
+            Value::Array(_) | Value::Map(_) => Err(CollectionError::InvalidKey(self.clone())),
+            // `i64::MIN` is -2^63, so this is every integer an `i64` holds.
+            Value::Float(Float(x))
+                if x.fract() == 0.0 && (i64::MIN as f64..-(i64::MIN as f64)).contains(&x) =>
+            {
+                Ok(Value::Num(x as i64))
+            }
+            #[cfg(feature = "bigint")]
+            Value::Float(Float(x)) if x.fract() == 0.0 => {
+                Ok(Value::integer(BigInt::from_f64(x).unwrap()))
+            }
+            _ => Ok(self.clone()),
+        }
+    }
+
+    /// A number used as an index, which may still be out of bounds.
+    pub(crate) fn as_index(&self) -> Result<i64, CollectionError> {
+        match self {
+            Value::Num(n) => Ok(*n),
+            value => Err(CollectionError::InvalidIndex(value.clone())),
+        }
+    }
 }
 
 impl From<bool> for Value {
@@ -55,3 +395,28 @@ impl From<bool> for Value {
         Value::Bool(value)
     }
 }
+
+/// `s` as it's written between the quotes of a string literal.
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '$' if chars.peek() == Some(&'{') => escaped.push_str("\\$"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Negative indices are never in bounds, so they map past the end of every
+/// array.
+pub(crate) fn to_usize(index: i64) -> usize {
+    usize::try_from(index).unwrap_or(usize::MAX)
+}
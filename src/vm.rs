@@ -1,362 +1,1189 @@
-use std::{cell::RefCell, collections::HashMap, process::exit, rc::Rc};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, BufRead, Stdout, Write},
+    rc::Rc,
+};
 
-use crate::{error::EvalError, expr::Expr, stmt::Stmt, value::Value};
+#[cfg(test)]
+use arbitrary::{Arbitrary, Unstructured};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
-pub struct VM {
+use crate::{
+    arithmetic::{ArithmeticOp, Overflow},
+    builtin::Builtin,
+    error::EvalError,
+    expr::Expr,
+    resolver::{Resolver, Slot},
+    stmt::Stmt,
+    value::Value,
+};
+
+/// How deep calls can nest by default. Each call of the program recurses on
+/// the host's stack, and this many calls of an ordinary function fit in the
+/// usual 8MiB of a main thread, even in a debug build.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+/// How a program finished. `T` is what running to completion produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionResult<T = ()> {
+    Completed(T),
+    /// The program ran `exit` with this code.
+    Exited(i32),
+}
+
+/// Why the tree-walker stopped evaluating before reaching the end of a body.
+/// `eval_stmt` propagates these outwards until something handles them: a loop
+/// handles `Break` and `Continue`, a call handles `Return`, and `eval` handles
+/// `Exit`.
+enum ControlFlow {
+    Return(Value),
+    Break,
+    Continue,
+    Exit(i32),
+    /// Boxed, so that the results passed back up through every level of the
+    /// recursion stay small.
+    Error(Box<EvalError>),
+}
+
+impl ControlFlow {
+    /// What a signal that nothing handled means for the program as a whole.
+    fn at_top_level<T>(self) -> Result<ExecutionResult<T>, EvalError> {
+        let outside = match self {
+            ControlFlow::Exit(code) => return Ok(ExecutionResult::Exited(code)),
+            ControlFlow::Error(e) => return Err(*e),
+            ControlFlow::Return(_) => "return from outside a function",
+            ControlFlow::Break => "break outside a loop",
+            ControlFlow::Continue => "continue outside a loop",
+        };
+        Err(EvalError::Error(format!("Cannot {outside}")))
+    }
+}
+
+impl<E: Into<EvalError>> From<E> for ControlFlow {
+    fn from(e: E) -> Self {
+        ControlFlow::Error(Box::new(e.into()))
+    }
+}
+
+/// The tree-walking interpreter. `print` writes to `writer`, and `reader`
+/// is where input is read from once the language can ask for it.
+pub struct VM<W: Write = Stdout> {
     pub body: Vec<Stmt>,
-    pub vars: Env<Value>,
-    pub fns: Env<Function>,
-    pub in_fn: bool,
-    pub return_val: Option<Value>,
+    /// The variables and functions declared at the top level, by name.
+    pub globals: HashMap<String, Value>,
+    /// The innermost local scope, or `None` at the top level.
+    scope: Option<Rc<RefCell<Env>>>,
+    pub overflow: Overflow,
+    pub writer: W,
+    reader: Option<Box<dyn BufRead>>,
+    /// How many calls are active.
+    depth: usize,
+    max_call_depth: usize,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
-pub struct Function {
-    args: Vec<String>,
-    body: Vec<Stmt>,
+/// A function value of the tree-walker. It holds on to the scope it was
+/// defined in, so its body sees that scope's variables wherever it's called.
+#[derive(Serialize, Deserialize)]
+pub struct Closure {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+    /// A deserialized closure only sees globals.
+    #[serde(skip)]
+    pub env: Option<Rc<RefCell<Env>>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Env<V> {
-    parent: Option<Rc<RefCell<Env<V>>>>,
-    values: HashMap<String, V>,
+impl Closure {
+    /// Closures are equal when they have the same code and captured the same
+    /// scope, so this compares the scope by identity.
+    fn key(&self) -> (&str, &[String], &[Stmt], Option<*const RefCell<Env>>) {
+        (
+            &self.name,
+            &self.params,
+            &self.body,
+            self.env.as_ref().map(Rc::as_ptr),
+        )
+    }
 }
 
-impl<V: Clone> Env<V> {
-    pub fn new() -> Self {
-        Self {
-            parent: None,
-            values: HashMap::new(),
-        }
+// The captured scope usually contains the closure itself, so none of these
+// look inside it.
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("body", &self.body)
+            .finish_non_exhaustive()
     }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Closure {}
+
+impl PartialOrd for Closure {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Closure {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+impl<'a> Arbitrary<'a> for Closure {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Closure {
+            name: u.arbitrary()?,
+            params: u.arbitrary()?,
+            body: u.arbitrary()?,
+            env: Default::default(),
+        })
+    }
+}
+
+/// A local scope of the tree-walker. Its variables sit in the slots the
+/// [`Resolver`] gave them, in the order they were declared.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Env {
+    parent: Option<Rc<RefCell<Env>>>,
+    slots: Vec<Value>,
+}
 
-    pub fn from(parent: &Rc<RefCell<Env<V>>>) -> Self {
+impl Env {
+    pub fn new(parent: Option<Rc<RefCell<Env>>>) -> Self {
         Env {
-            parent: Some(Rc::clone(parent)),
-            values: HashMap::new(),
+            parent,
+            slots: vec![],
         }
     }
 
-    pub fn define(&mut self, name: &str, value: V) {
-        self.values.insert(name.to_string(), value);
+    pub fn define(&mut self, value: Value) {
+        self.slots.push(value);
     }
 
-    pub fn get(&self, key: &str) -> Result<V, EvalError> {
-        if let Some(value) = self.values.get(key) {
-            Ok((*value).clone())
-        } else if let Some(ref enclosing) = self.parent {
-            enclosing.borrow().get(key)
-        } else {
-            Err(EvalError::Error(format!("Undefined variable '{}'", key)))
+    pub fn get(&self, slot: Slot) -> Option<Value> {
+        match slot.depth {
+            0 => self.slots.get(slot.index).cloned(),
+            depth => self.parent.as_ref()?.borrow().get(Slot {
+                depth: depth - 1,
+                ..slot
+            }),
         }
     }
 
-    pub fn assign(&mut self, key: &str, value: V) -> Result<(), EvalError> {
-        if self.values.contains_key(key) {
-            self.values.insert(key.to_string(), value);
-            Ok(())
-        } else if let Some(ref enclosing) = self.parent {
-            enclosing.borrow_mut().assign(key, value)
-        } else {
-            Err(EvalError::Error(format!("Undefined variable '{}'", key)))
+    /// Returns `None` if the slot doesn't exist.
+    pub fn assign(&mut self, slot: Slot, value: Value) -> Option<()> {
+        match slot.depth {
+            0 => *self.slots.get_mut(slot.index)? = value,
+            depth => self.parent.as_ref()?.borrow_mut().assign(
+                Slot {
+                    depth: depth - 1,
+                    ..slot
+                },
+                value,
+            )?,
         }
+        Some(())
     }
 }
 
-impl<V: Clone> Default for Env<V> {
+impl Default for VM<Stdout> {
     fn default() -> Self {
-        Self::new()
+        Self::new(io::stdout())
     }
 }
 
-impl VM {
-    pub fn eval(&mut self, instructions: &[Stmt]) -> Result<Self, EvalError> {
-        for stmt in instructions {
+impl<W: Write> VM<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            body: vec![],
+            globals: HashMap::new(),
+            scope: None,
+            overflow: Overflow::default(),
+            writer,
+            reader: None,
+            depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+
+    /// Limits how many calls may be active at once. Each one recurses on the
+    /// host's stack, so exceeding it is an error rather than a crash.
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Chooses what integer arithmetic does on overflow, in place of
+    /// [`Overflow::default`], which depends on the `bigint` feature.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Gives the program somewhere to read input from. Without a reader,
+    /// every read behaves as if the input had ended.
+    pub fn with_reader(mut self, reader: impl BufRead + 'static) -> Self {
+        self.reader = Some(Box::new(reader));
+        self
+    }
+
+    /// Reads one line of input without its line ending, or `None` once the
+    /// input has ended.
+    pub fn read_line(&mut self) -> Result<Option<String>, EvalError> {
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(None);
+        };
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(Some(line))
+    }
+
+    /// Forgets every definition, keeping the streams and settings.
+    pub fn reset(&mut self) {
+        self.body.clear();
+        self.globals.clear();
+        self.scope = None;
+    }
+
+    pub fn eval(&mut self, instructions: &[Stmt]) -> Result<ExecutionResult, EvalError> {
+        let resolved = Resolver::default()
+            .with_globals(self.globals.keys())
+            .resolve(instructions)?;
+        for (stmt, resolved) in instructions.iter().zip(&resolved) {
             self.body.push(stmt.clone());
-            self.eval_stmt(stmt)?;
+            if let Err(flow) = self.eval_stmt(resolved) {
+                return flow.at_top_level();
+            }
         }
-        Ok(self.clone())
+        Ok(ExecutionResult::Completed(()))
     }
 
-    pub fn eval_stmt(&mut self, stmt: &Stmt) -> Result<(), EvalError> {
-        match stmt {
-            Stmt::Exit(expr) => {
-                let expr = self.eval_expr(expr)?;
-                match expr {
-                    Expr::Literal(Value::Num(n)) => exit(n as i32),
-                    _ => {
-                        return Err(EvalError::Error(format!(
-                            "Gave the wrong type {expr} to exit"
-                        )))
-                    }
-                }
+    /// Evaluates a single expression, as the repl does to echo its value.
+    pub fn eval_value(&mut self, expr: &Expr) -> Result<ExecutionResult<Value>, EvalError> {
+        let expr = Resolver::default()
+            .with_globals(self.globals.keys())
+            .resolve_expr(expr)?;
+        match self.eval_expr(&expr) {
+            Ok(Expr::Literal(value)) => Ok(ExecutionResult::Completed(value)),
+            Ok(expr) => Err(EvalError::Error(format!(
+                "{expr} did not evaluate to a value"
+            ))),
+            Err(flow) => flow.at_top_level(),
+        }
+    }
+
+    /// Runs `f` in a new scope nested in `parent`, then returns to the
+    /// current scope.
+    fn scoped<T>(&mut self, parent: Option<Rc<RefCell<Env>>>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let scope = Rc::new(RefCell::new(Env::new(parent)));
+        let outer = self.scope.replace(scope);
+        let res = f(self);
+        self.scope = outer;
+        res
+    }
+
+    /// Declares a variable in the current scope: the next slot of a local
+    /// scope, or a global at the top level.
+    fn define(&mut self, name: &str, value: Value) {
+        match &self.scope {
+            Some(scope) => scope.borrow_mut().define(value),
+            None => {
+                self.globals.insert(name.to_string(), value);
             }
-            Stmt::Print(expr) => {
-                let expr = self.eval_expr(expr)?;
-                println!("{}", expr);
+        }
+    }
+
+    fn get_var(&self, var: &Expr) -> Result<Value, EvalError> {
+        let value = match var {
+            Expr::Var(name) => self
+                .globals
+                .get(name)
+                .cloned()
+                .or_else(|| Builtin::named(name).map(Value::Builtin)),
+            Expr::Local(_, slot) => self
+                .scope
+                .as_ref()
+                .and_then(|scope| scope.borrow().get(*slot)),
+            _ => return Err(EvalError::Error(format!("{var} is not a variable"))),
+        };
+        value.ok_or_else(|| undefined_variable(var))
+    }
+
+    fn set_var(&mut self, var: &Expr, value: Value) -> Result<(), EvalError> {
+        let assigned = match var {
+            Expr::Var(name) => self.globals.get_mut(name).map(|global| *global = value),
+            Expr::Local(_, slot) => self
+                .scope
+                .as_ref()
+                .and_then(|scope| scope.borrow_mut().assign(*slot, value)),
+            _ => return Err(EvalError::Error(format!("Cannot assign to {var}"))),
+        };
+        assigned.ok_or_else(|| undefined_variable(var))
+    }
+
+    fn closure(&self, name: &str, params: &[String], body: &[Stmt]) -> Value {
+        Value::Closure(Rc::new(Closure {
+            name: name.to_string(),
+            params: params.to_vec(),
+            body: body.to_vec(),
+            env: self.scope.clone(),
+        }))
+    }
+
+    /// Runs `stmts` in a new scope nested in the current one.
+    fn block(&mut self, stmts: &[Stmt]) -> Result<(), ControlFlow> {
+        let parent = self.scope.clone();
+        self.scoped(parent, |vm| vm.eval_stmts(stmts))
+    }
+
+    /// Runs a function body in the current scope, turning a `return` into the
+    /// value of the call.
+    fn call_body(&mut self, body: &[Stmt]) -> Result<Expr, ControlFlow> {
+        match self.eval_stmts(body) {
+            Ok(()) => Ok(Expr::Literal(Value::Null)),
+            Err(ControlFlow::Return(value)) => Ok(Expr::Literal(value)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn condition(&mut self, cond: &Expr) -> Result<bool, ControlFlow> {
+        Ok(matches!(self.eval_expr(cond)?, Expr::Literal(value) if value.is_truthy()))
+    }
+
+    /// Runs one iteration of a loop, returning whether the loop goes on.
+    fn loop_body(&mut self, body: &[Stmt]) -> Result<bool, ControlFlow> {
+        match self.block(body) {
+            Ok(()) | Err(ControlFlow::Continue) => Ok(true),
+            Err(ControlFlow::Break) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn for_loop(
+        &mut self,
+        init: Option<&Stmt>,
+        cond: &Expr,
+        step: Option<&Expr>,
+        body: &[Stmt],
+    ) -> Result<(), ControlFlow> {
+        if let Some(init) = init {
+            self.eval_stmt(init)?;
+        }
+        while self.condition(cond)? {
+            if !self.loop_body(body)? {
+                break;
+            }
+            if let Some(step) = step {
+                self.eval_expr(step)?;
             }
+        }
+        Ok(())
+    }
+
+    fn eval_stmts(&mut self, stmts: &[Stmt]) -> Result<(), ControlFlow> {
+        for stmt in stmts {
+            self.eval_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn eval_stmt(&mut self, stmt: &Stmt) -> Result<(), ControlFlow> {
+        match stmt {
+            Stmt::Exit(expr) => return Err(self.exit(expr)),
+            Stmt::Print(expr) => self.print(expr)?,
             Stmt::Expr(expr) => {
                 self.eval_expr(expr)?;
             }
-            Stmt::If(cond, body) => {
-                let cond = self.eval_expr(cond)?;
-                if let Expr::Literal(value) = cond {
-                    if value.is_truthy() {
-                        *self = Self::eval(self, body)?.clone();
-                    }
+            Stmt::If(cond, body, otherwise) => {
+                if self.condition(cond)? {
+                    self.block(body)?;
+                } else if let Some(otherwise) = otherwise {
+                    self.block(otherwise)?;
                 }
             }
-            Stmt::Block(stmts) => {
-                let old_vars = self.vars.clone();
-                self.vars = Env::from(&Rc::new(RefCell::new(self.vars.clone())));
-                self.vars.values = HashMap::default();
-                *self = self.eval(stmts)?.clone();
-                self.vars = old_vars;
-            }
+            Stmt::Block(stmts) => self.block(stmts)?,
             Stmt::Assign(s, expr) => {
                 let expr = self.eval_expr(expr)?;
                 if let Expr::Literal(value) = expr {
-                    self.vars.define(s, value);
+                    self.define(s, value);
                 }
             }
-            Stmt::Func(name, args, body) => self.fns.define(
-                name,
-                Function {
-                    args: args.to_vec(),
-                    body: body.to_vec(),
-                },
-            ),
-            Stmt::Return(expr) => {
-                let expr = self.eval_expr(expr)?;
-                if self.in_fn {
-                    self.return_val = match expr {
-                        Expr::Literal(value) => Some(value),
-                        _ => unreachable!(),
+            Stmt::Func(name, args, body) => {
+                let closure = self.closure(name, args, body);
+                self.define(name, closure);
+            }
+            Stmt::Return(expr) => return Err(ControlFlow::Return(self.value(expr)?)),
+            Stmt::While(cond, body) => {
+                while self.condition(cond)? {
+                    if !self.loop_body(body)? {
+                        break;
                     }
                 }
             }
-            Stmt::While(cond, body) => loop {
-                let cond = self.eval_expr(cond)?;
-                if cond == Expr::Literal(Value::Bool(false)) {
-                    break;
-                }
-                *self = self.eval(body)?.clone();
-            },
+            Stmt::For(init, cond, step, body) => {
+                let parent = self.scope.clone();
+                self.scoped(parent, |vm| {
+                    vm.for_loop(init.as_deref(), cond, step.as_ref(), body)
+                })?
+            }
+            Stmt::Break => return Err(ControlFlow::Break),
+            Stmt::Continue => return Err(ControlFlow::Continue),
         }
         Ok(())
     }
 
-    pub fn eval_expr(&mut self, expr: &Expr) -> Result<Expr, EvalError> {
+    // The larger cases of `eval_stmt` and `eval_expr` are methods of their
+    // own, which keeps the stack frames of those two small. Every call in the
+    // program recurses through both, so their size limits how deep calls go.
+
+    fn print(&mut self, expr: &Expr) -> Result<(), ControlFlow> {
+        let expr = self.eval_expr(expr)?;
+        writeln!(self.writer, "{}", expr)?;
+        Ok(())
+    }
+
+    /// The signal that `exit expr` stops the program with.
+    fn exit(&mut self, expr: &Expr) -> ControlFlow {
+        let expr = match self.eval_expr(expr) {
+            Ok(expr) => expr,
+            Err(e) => return e,
+        };
         match expr {
-            Expr::Literal(l) => match l {
-                Value::Bool(_) | Value::Num(_) | Value::String(_) | Value::Null => Ok(expr.clone()),
-                Value::Array(vec) => {
-                    let mut items = vec![];
-                    for expr in vec {
-                        items.push(self.eval_expr(expr)?);
-                    }
-                    Ok(Expr::Literal(Value::Array(items)))
-                }
+            Expr::Literal(Value::Num(n)) => match i32::try_from(n) {
+                Ok(code) => ControlFlow::Exit(code),
+                Err(_) => EvalError::Error(format!("Exit code {n} is out of range")).into(),
             },
-            Expr::Add(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                match (left, right) {
-                    (Expr::Literal(Value::Num(l)), Expr::Literal(Value::Num(r))) => {
-                        Ok(Expr::Literal(Value::Num(l + r)))
-                    }
-                    (Expr::Literal(Value::String(x)), Expr::Literal(Value::String(y))) => {
-                        let mut res = x.to_string();
-                        res.push_str(&y);
-                        Ok(Expr::Literal(Value::String(res)))
-                    }
-                    (l, r) => Err(EvalError::InvalidBinaryExpr(l, "+".to_string(), r)),
-                }
-            }
-            Expr::Sub(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-
-                match (left, right) {
-                    (Expr::Literal(Value::Num(l)), Expr::Literal(Value::Num(r))) => {
-                        Ok(Expr::Literal(Value::Num(l - r)))
-                    }
-                    (l, r) => Err(EvalError::InvalidBinaryExpr(l, "-".to_string(), r)),
-                }
-            }
-            Expr::Mul(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                match (left, right) {
-                    (Expr::Literal(Value::Num(l)), Expr::Literal(Value::Num(r))) => {
-                        Ok(Expr::Literal(Value::Num(l * r)))
-                    }
-                    (l, r) => Err(EvalError::InvalidBinaryExpr(l, "*".to_string(), r)),
-                }
-            }
-            Expr::Div(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                match (left, right) {
-                    (Expr::Literal(Value::Num(l)), Expr::Literal(Value::Num(r))) => {
-                        Ok(Expr::Literal(Value::Num(l / r)))
-                    }
-                    (l, r) => Err(EvalError::InvalidBinaryExpr(l, "/".to_string(), r)),
-                }
-            }
-            Expr::Not(b) => {
-                let b = self.eval_expr(b)?;
-                match b {
-                    Expr::Literal(Value::Bool(b)) => Ok(Expr::Literal(Value::Bool(!b))),
-                    _ => Err(EvalError::InvalidUnaryExpr("!".to_string(), b)),
-                }
-            }
-            Expr::EqualEqual(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                Ok((left == right).into())
-            }
-            Expr::And(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
+            _ => EvalError::Error(format!("Gave the wrong type {expr} to exit")).into(),
+        }
+    }
 
-                match (left, right) {
-                    (Expr::Literal(l), Expr::Literal(r)) => {
-                        Ok(Expr::Literal(Value::Bool(l.is_truthy() && r.is_truthy())))
-                    }
-                    _ => Ok(Expr::Literal(Value::Bool(false))),
-                }
-            }
-            Expr::Or(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                match (left, right) {
-                    (Expr::Literal(l), Expr::Literal(r)) => {
-                        Ok(Expr::Literal(Value::Bool(l.is_truthy() || r.is_truthy())))
-                    }
-                    _ => Ok(Expr::Literal(Value::Bool(false))),
-                }
-            }
-            Expr::Var(name) => match self.vars.get(name) {
-                Ok(val) => Ok(Expr::Literal(val.clone())),
-                Err(e) => Err(e),
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Expr, ControlFlow> {
+        match expr {
+            Expr::Literal(_) => Ok(expr.clone()),
+            Expr::Array(items) => self.array(items),
+            Expr::Interpolation(parts) => self.interpolation(parts),
+            Expr::Map(entries) => self.map(entries),
+            Expr::Index(array, index) => self.index(array, index),
+            Expr::Add(x, y) => self.binary(ArithmeticOp::Add, x, y),
+            Expr::Sub(x, y) => self.binary(ArithmeticOp::Sub, x, y),
+            Expr::Mul(x, y) => self.binary(ArithmeticOp::Mul, x, y),
+            Expr::Div(x, y) => self.binary(ArithmeticOp::Div, x, y),
+            Expr::Mod(x, y) => self.binary(ArithmeticOp::Mod, x, y),
+            Expr::Not(b) => self.not(b),
+            Expr::EqualEqual(x, y) => self.comparison(x, y, Ordering::is_eq),
+            // Both evaluate to whichever operand decided the outcome, and
+            // only evaluate the right one if the left didn't.
+            Expr::And(x, y) => match self.eval_expr(x)? {
+                Expr::Literal(value) if !value.is_truthy() => Ok(Expr::Literal(value)),
+                _ => self.eval_expr(y),
             },
-            Expr::Call(name, args) => {
-                // get the function itself
-                let body = &self.fns.get(name)?;
-                let old_vars = self.vars.clone();
-                self.vars = Env::from(&Rc::new(RefCell::new(self.vars.clone())));
-                self.vars.values = HashMap::default();
-                for (i, _) in args.iter().enumerate() {
-                    let arg = self.eval_expr(&args[i])?;
-                    match arg {
-                        Expr::Literal(value) => {
-                            self.vars.define(&body.args[i], value);
-                        }
-                        _ => return Err(EvalError::Error("Arg was not valid".to_string())),
-                    }
-                }
-                let res = self.eval_expr(&Expr::FnBody(body.body.clone()))?.clone();
-                self.vars = old_vars;
-                Ok(res)
+            Expr::Or(x, y) => match self.eval_expr(x)? {
+                Expr::Literal(value) if value.is_truthy() => Ok(Expr::Literal(value)),
+                _ => self.eval_expr(y),
+            },
+            Expr::Var(_) | Expr::Local(..) => Ok(Expr::Literal(self.get_var(expr)?)),
+            Expr::Lambda(params, body) => {
+                Ok(Expr::Literal(self.closure("anonymous", params, body)))
             }
+            Expr::Call(callee, args) => self.call_expr(callee, args),
             Expr::FnBody(body) => {
-                self.in_fn = true;
-                for stmt in body {
-                    let mut ret_val: Option<Expr> = None;
-                    if let Some(saved_val) = &self.return_val {
-                        ret_val = Some(Expr::Literal(saved_val.clone()));
-                    }
-                    if ret_val.is_some() {
-                        self.return_val = None;
-                        return Ok(ret_val.unwrap());
-                    }
-                    self.eval_stmt(stmt)?;
-                }
-                self.in_fn = false;
+                let parent = self.scope.clone();
+                self.scoped(parent, |vm| vm.call_body(body))
+            }
+            Expr::UnaryPlus(expr) => self.unary_plus(expr),
+            Expr::UnaryMinus(expr) => self.unary_minus(expr),
+            Expr::NotEqual(x, y) => self.comparison(x, y, Ordering::is_ne),
+            Expr::LessThan(x, y) => self.comparison(x, y, Ordering::is_lt),
+            Expr::LessThanEqual(x, y) => self.comparison(x, y, Ordering::is_le),
+            Expr::GreaterThan(x, y) => self.comparison(x, y, Ordering::is_gt),
+            Expr::GreaterThanEqual(x, y) => self.comparison(x, y, Ordering::is_ge),
+            Expr::Assign(target, value) => self.assign(target, value),
+            Expr::CompoundAssign(op, target, value) => self.compound_assign(*op, target, value),
+            Expr::Postfix(op, target) => self.postfix(*op, target),
+        }
+    }
 
-                Ok(Expr::Literal(Value::Null))
+    fn array(&mut self, items: &[Expr]) -> Result<Expr, ControlFlow> {
+        let mut values = vec![];
+        for item in items {
+            values.push(self.value(item)?);
+        }
+        Ok(Expr::Literal(Value::array(values)))
+    }
+
+    fn interpolation(&mut self, parts: &[Expr]) -> Result<Expr, ControlFlow> {
+        let mut s = String::new();
+        for part in parts {
+            s.push_str(&self.value(part)?.text());
+        }
+        Ok(Expr::Literal(Value::String(s)))
+    }
+
+    fn map(&mut self, entries: &[(Expr, Expr)]) -> Result<Expr, ControlFlow> {
+        let mut map = BTreeMap::new();
+        for (key, value) in entries {
+            let key = self.value(key)?.to_key()?;
+            map.insert(key, self.value(value)?);
+        }
+        Ok(Expr::Literal(Value::map(map)))
+    }
+
+    fn index(&mut self, array: &Expr, index: &Expr) -> Result<Expr, ControlFlow> {
+        let (array, index) = (self.value(array)?, self.value(index)?);
+        Ok(Expr::Literal(array.get_index(&index)?))
+    }
+
+    fn not(&mut self, b: &Expr) -> Result<Expr, ControlFlow> {
+        let b = self.eval_expr(b)?;
+        match b {
+            Expr::Literal(Value::Bool(b)) => Ok(Expr::Literal(Value::Bool(!b))),
+            _ => Err(EvalError::InvalidUnaryExpr("!".to_string(), b).into()),
+        }
+    }
+
+    fn call_expr(&mut self, callee: &Expr, args: &[Expr]) -> Result<Expr, ControlFlow> {
+        match self.eval_expr(callee)? {
+            Expr::Literal(Value::Closure(closure)) => {
+                let values = self.args(&closure.name, closure.params.len(), args)?;
+                self.call(&closure, values)
             }
-            Expr::UnaryPlus(expr) => {
-                let expr = self.eval_expr(expr)?;
-                match expr {
-                    Expr::Literal(Value::Num(n)) => Ok(Expr::Literal(Value::Num(n.abs()))),
-                    _ => Err(EvalError::Error(
-                        "Unary plus can only be applied to numbers".to_string(),
-                    )),
-                }
+            Expr::Literal(Value::Builtin(builtin)) => {
+                let values = self.args(builtin.name(), builtin.arity(), args)?;
+                Ok(Expr::Literal(builtin.call(&values)?))
             }
-            Expr::UnaryMinus(expr) => {
-                let expr = self.eval_expr(expr)?;
-                match expr {
-                    Expr::Literal(Value::Num(n)) => Ok(Expr::Literal(Value::Num(-n))),
-                    _ => Err(EvalError::Error(
-                        "Unary minus can only be applied to numbers".to_string(),
-                    )),
-                }
+            callee => Err(EvalError::Error(format!("{callee} is not a function")).into()),
+        }
+    }
+
+    fn unary_plus(&mut self, expr: &Expr) -> Result<Expr, ControlFlow> {
+        let value = self.value(expr)?;
+        match self.overflow.unary_plus(&value) {
+            Some(res) => Ok(Expr::Literal(res?)),
+            None => Err(
+                EvalError::Error("Unary plus can only be applied to numbers".to_string()).into(),
+            ),
+        }
+    }
+
+    fn unary_minus(&mut self, expr: &Expr) -> Result<Expr, ControlFlow> {
+        let value = self.value(expr)?;
+        match self.overflow.unary_minus(&value) {
+            Some(res) => Ok(Expr::Literal(res?)),
+            None => Err(
+                EvalError::Error("Unary minus can only be applied to numbers".to_string()).into(),
+            ),
+        }
+    }
+
+    /// An indexed target is evaluated before the value, as in the bytecode
+    /// VM.
+    fn assign(&mut self, target: &Expr, value: &Expr) -> Result<Expr, ControlFlow> {
+        let value = match target {
+            Expr::Index(array, index) => {
+                let (array, index) = (self.value(array)?, self.value(index)?);
+                let value = self.value(value)?;
+                array.set_index(&index, value.clone())?;
+                value
             }
-            Expr::NotEqual(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                Ok((left != right).into())
+            _ => {
+                let value = self.value(value)?;
+                self.set_var(target, value.clone())?;
+                value
             }
-            Expr::LessThan(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                Ok((left < right).into())
+        };
+        Ok(Expr::Literal(value))
+    }
+
+    /// The target is read before the value runs.
+    fn compound_assign(
+        &mut self,
+        op: ArithmeticOp,
+        target: &Expr,
+        value: &Expr,
+    ) -> Result<Expr, ControlFlow> {
+        let (_, new) = self.update(target, |vm, current| {
+            let value = vm.eval_expr(value)?;
+            vm.arithmetic(op, Expr::Literal(current), value)
+        })?;
+        Ok(Expr::Literal(new))
+    }
+
+    fn postfix(&mut self, op: ArithmeticOp, target: &Expr) -> Result<Expr, ControlFlow> {
+        let (old, _) = self.update(target, |vm, current| {
+            vm.arithmetic(op, Expr::Literal(current), 1.into())
+        })?;
+        Ok(Expr::Literal(old))
+    }
+
+    /// Replaces the value at `target` with `f` of it, and returns the old and
+    /// new values.
+    fn update(
+        &mut self,
+        target: &Expr,
+        f: impl FnOnce(&mut Self, Value) -> Result<Value, ControlFlow>,
+    ) -> Result<(Value, Value), ControlFlow> {
+        match target {
+            Expr::Index(array, index) => {
+                let (array, index) = (self.value(array)?, self.value(index)?);
+                let old = array.get_index(&index)?;
+                let new = f(self, old.clone())?;
+                array.set_index(&index, new.clone())?;
+                Ok((old, new))
             }
-            Expr::LessThanEqual(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                Ok((left <= right).into())
+            _ => {
+                let old = self.get_var(target)?;
+                let new = f(self, old.clone())?;
+                self.set_var(target, new.clone())?;
+                Ok((old, new))
             }
-            Expr::GreaterThan(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                Ok((left > right).into())
+        }
+    }
+
+    fn value(&mut self, expr: &Expr) -> Result<Value, ControlFlow> {
+        match self.eval_expr(expr)? {
+            Expr::Literal(value) => Ok(value),
+            expr => Err(EvalError::Error(format!("{expr} did not evaluate to a value")).into()),
+        }
+    }
+
+    fn call(&mut self, closure: &Closure, args: Vec<Value>) -> Result<Expr, ControlFlow> {
+        if self.depth >= self.max_call_depth {
+            return Err(EvalError::Error(format!(
+                "Maximum call depth of {} exceeded",
+                self.max_call_depth
+            ))
+            .into());
+        }
+        // The body runs in a scope of its own inside the one the function was
+        // defined in, not the caller's.
+        self.depth += 1;
+        let res = self.scoped(closure.env.clone(), |vm| {
+            for (param, value) in closure.params.iter().zip(args) {
+                vm.define(param, value);
             }
-            Expr::GreaterThanEqual(x, y) => {
-                let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
-                Ok((left >= right).into())
+            vm.call_body(&closure.body)
+        });
+        self.depth -= 1;
+        res
+    }
+
+    /// Evaluates the arguments of a call to `name` after checking there are
+    /// `arity` of them.
+    fn args(&mut self, name: &str, arity: usize, args: &[Expr]) -> Result<Vec<Value>, ControlFlow> {
+        if args.len() != arity {
+            return Err(EvalError::Error(format!(
+                "{name} expects {arity} arguments but got {}",
+                args.len()
+            ))
+            .into());
+        }
+        args.iter().map(|arg| self.value(arg)).collect()
+    }
+
+    fn comparison(
+        &mut self,
+        x: &Expr,
+        y: &Expr,
+        f: fn(Ordering) -> bool,
+    ) -> Result<Expr, ControlFlow> {
+        let (left, right) = (self.value(x)?, self.value(y)?);
+        Ok(f(left.compare(&right)).into())
+    }
+
+    fn binary(&mut self, op: ArithmeticOp, x: &Expr, y: &Expr) -> Result<Expr, ControlFlow> {
+        let (left, right) = (self.eval_expr(x)?, self.eval_expr(y)?);
+        Ok(Expr::Literal(self.arithmetic(op, left, right)?))
+    }
+
+    /// Applies `op` to two evaluated operands. `+` also joins strings.
+    fn arithmetic(&self, op: ArithmeticOp, left: Expr, right: Expr) -> Result<Value, ControlFlow> {
+        if let (Expr::Literal(l), Expr::Literal(r)) = (&left, &right) {
+            if let Some(res) = self.overflow.numbers(op, l, r) {
+                return Ok(res?);
             }
-            Expr::AddAssign(var, incr) => {
-                let incr = self.eval_expr(incr)?;
-                let count = match incr {
-                    Expr::Literal(Value::Num(n)) => Ok(n),
-                    _ => Err(EvalError::Error(
-                        "Add Assign can only be applied to numbers".to_string(),
-                    )),
-                };
-                match **var {
-                    Expr::Var(ref name) => match self.vars.get(name) {
-                        Ok(val) => match val {
-                            Value::Num(n) => {
-                                self.vars.define(name, Value::Num(n + count?));
-                                Ok(Expr::Literal(self.vars.get(name)?))
-                            }
-                            _ => Err(EvalError::Error("Could not find variable".to_string())),
-                        },
-                        Err(e) => Err(e),
-                    },
-                    _ => unreachable!(),
-                }
+        }
+        match (left, right) {
+            (Expr::Literal(Value::String(mut l)), Expr::Literal(Value::String(r)))
+                if op == ArithmeticOp::Add =>
+            {
+                l.push_str(&r);
+                Ok(Value::String(l))
             }
+            (l, r) => Err(EvalError::InvalidBinaryExpr(l, op.to_string(), r).into()),
         }
     }
 }
 
+fn undefined_variable(var: &Expr) -> EvalError {
+    EvalError::Error(format!("Undefined variable '{var}'"))
+}
+
 #[cfg(test)]
 mod test {
-    use crate::{stmt::Stmt, vm::VM};
+    use crate::{
+        arithmetic::Overflow,
+        bytecode,
+        parser::Parser,
+        stmt::Stmt,
+        tokenizer::Tokenizer,
+        vm::{ExecutionResult, VM},
+    };
     use arbtest::arbtest;
     use insta::assert_yaml_snapshot as test;
+    use std::{
+        sync::mpsc::{self, RecvTimeoutError},
+        time::Duration,
+    };
+
+    fn parse(program: &str) -> Vec<Stmt> {
+        let tokens = Tokenizer::default().tokenize(program).unwrap();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    /// Runs `program` on both VMs, checks they print the same thing and
+    /// returns that.
+    fn both(program: &str) -> String {
+        let program = parse(program);
+        let mut tree_walker = VM::new(vec![]);
+        tree_walker.eval(&program).unwrap();
+
+        let code = bytecode::Compiler::default().compile(&program).unwrap();
+        let mut out = vec![];
+        bytecode::VM::new(&mut out).eval(&code).unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&tree_walker.writer),
+            String::from_utf8_lossy(&out)
+        );
+        String::from_utf8(out).unwrap()
+    }
 
     #[test]
     fn test_if() {
         let stmts: Vec<Stmt> = vec![Stmt::Print(20.into())];
-        let mut vm = VM::default();
-        test!(vm.eval(&stmts).unwrap().return_val)
+        let mut vm = VM::new(vec![]);
+        vm.eval(&stmts).unwrap();
+        test!(String::from_utf8(vm.writer).unwrap())
     }
 
     #[test]
-    fn no_crash() {
-        arbtest(|input| {
-            let stmts: Vec<Stmt> = input.arbitrary()?;
-            let mut vm = VM::default();
-            match vm.eval(&stmts) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(arbitrary::Error::NotEnoughData),
+    fn print() {
+        let mut vm = VM::new(vec![]);
+        vm.eval(&parse(
+            "let x = 2; print x * 3; print \"hi\"; print [true, null, []]; if x == 2 { print x; }",
+        ))
+        .unwrap();
+        test!(String::from_utf8(vm.writer).unwrap());
+    }
+
+    #[test]
+    fn same_output_as_bytecode() {
+        both(
+            "print 1 + 2; print 0 - 7; print \"a\" + \"b\"; print !true; print null; print [1, [\"x\"], []];",
+        );
+    }
+
+    #[test]
+    fn scoping() {
+        test!(both(
+            "let x = 1; let y = 0;
+             { x = x + 1; let x = 10; x = x + 1; y = x; }
+             print x; print y;
+             let i = 0; while i < 3 { let sq = i * i; y = sq; i = i + 1; }
+             print i; print y;
+             if true { let i = 100; x = i; } print i; print x;
+             fn set(v) { x = v; let y = v; } set(42); print x; print y;
+             let a = 0; let b = a = 5; print a; print b;"
+        ));
+    }
+
+    #[test]
+    fn if_else() {
+        test!(both(
+            "fn classify(n) {
+                 if n < 0 { return \"negative\"; } elif n == 0 { return \"zero\"; }
+                 elif n < 10 { return \"small\"; } else { return \"large\"; }
+             }
+             print classify(0 - 3); print classify(0); print classify(7); print classify(12);
+             let x = 1; if x > 1 { let x = 2; } else { let x = 3; print x; } print x;"
+        ));
+    }
+
+    #[test]
+    fn loops() {
+        test!(both(
+            "for (let i = 0; i < 10; i++) { if i == 2 { continue; } if i == 5 { break; } print i; }
+             let n = 3; while true { let half = n / 2; { let seen = n; if seen == 0 { break; } } n--; }
+             print n;
+             let found = 0;
+             for (let a = 1; a < 5; a++) {
+                 for (let b = 1; b < 5; b++) { if b > a { break; } if a * b == 6 { found = a * 10 + b; } }
+                 if found > 0 { break; }
+             }
+             print found;
+             let i = 0; for (;;) { i++; if i < 3 { continue; } break; } print i;
+             fn first_even(xs) { for (let i = 0; ; i++) { if i == 3 { return null; } if i * 2 == xs { return i; } } }
+             print first_even(4); print first_even(7);"
+        ));
+    }
+
+    #[test]
+    fn compound_assignment() {
+        test!(both(
+            "let x = 10; x -= 3; print x; x *= 4; print x; x /= 3; print x; x %= 5; print x;
+             print 7 % 3; print -7 % 3; print 7 % -3; print -7 % -3;
+             let s = \"a\"; s += \"b\"; print s;
+             let n = 0; fn bump(step) { return n += step; } bump(5); print bump(-2);
+             { let y = 2; y %= 2; print y--; print y; }"
+        ));
+    }
+
+    #[test]
+    fn postfix() {
+        test!(both(
+            "let x = 6; print x--; print x; print x++ + x;
+             let xs = [1, {\"n\": 2}]; print xs[0]++; print xs[1][\"n\"]--; print xs;
+             fn f() { let i = 0; return fn() { return i++; }; } let g = f(); g(); print g();"
+        ));
+    }
+
+    #[test]
+    fn arrays() {
+        test!(both(
+            "let xs = [1, 2, 3]; xs[0] = 10; xs[1] += 5; xs[2]++; print xs; print xs[1 + 1];
+             let ys = xs; push(ys, 4); print xs; print len(xs); print pop(xs); print ys;
+             print slice(xs, 1, 3); print slice(xs, 3, 3); print concat(xs, [\"a\", [null]]);
+             let grid = [[0, 0], [0, 0]]; grid[1][0] = 7; print grid;
+             for (let i = 0; i < 3; i++) { let fresh = []; push(fresh, i); print fresh; }
+             fn first(a) { return a[0]; } print first([[5]])[0]; print [1, [2]] == [1, [2]];
+             let len = 1; print len;
+             let shared = [0]; let pair = [shared, shared]; push(pair, shared); print pair;"
+        ));
+    }
+
+    #[test]
+    fn maps() {
+        test!(both(
+            "let m = {\"b\": 2, \"a\": 1, 3: [true], null: {}}; print m; print m[\"a\"] + m[\"b\"];
+             m[\"c\"] = 3; m[\"a\"] += 10; m[3][0] = false; print keys(m); print values(m);
+             print len(m); print has(m, \"c\"); print has(m, \"d\"); print {1: \"x\", 1: \"y\"};
+             let alias = m; alias[null][\"deep\"] = 1; print m[null];
+             let counts = {}; let words = [\"a\", \"b\", \"a\"];
+             for (let i = 0; i < len(words); i++) {
+                 let w = words[i]; if has(counts, w) { counts[w]++; } else { counts[w] = 1; }
+             }
+             print counts; print {2: 0, 1: 0} == {1: 0, 2: 0}; print {} || \"empty\";
+             let inner = {}; let outer = {1: inner}; outer[2] = inner; print outer;"
+        ));
+    }
+
+    #[test]
+    fn floats() {
+        test!(both(
+            "print 1.5 + 2; print 1 - 0.25; print 3 * 0.5; print 1 / 4.0; print 7.5 % 2;
+             print 10 / 4; print 2e3; print 1.0; print -1.5; print +(0 - 2.5);
+             print 1 == 1.0; print 1 < 1.5; print 2.5 >= 3; print 0.0 == -0.0;
+             print 1.0 / 0; print -1 / 0.0; let nan = 0.0 / 0; print nan; print nan == nan;
+             print [1, 2.0] == [1.0, 2]; print 0.0 || \"zero\";
+             print {1: 1} == {1.0: 1.0}; print {\"a\": [2]} != {\"a\": [2.0]}; print {1: 1} < {1: 1.5};
+             let m = {1.5: \"a\", 2: \"b\"}; print m[1.5]; print m;
+             print {1: \"a\"}[1.0]; m[2.0] += \"c\"; m[-0.0] = 0; print m; print has(m, 0);
+             m[nan] = \"n\"; print m[0.0 / 0]; print has(m, 1e300);"
+        ));
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn big_integers() {
+        test!(both(
+            "fn fact(n) { if n < 2 { return 1; } return n * fact(n - 1); }
+             print fact(25); print fact(25) / fact(23); print 9223372036854775807 + 1 - 1;
+             print -(0 - 9223372036854775807 - 1); print +(0 - 99999999999999999999);
+             print 99999999999999999999 % 7; print 99999999999999999999 > 9223372036854775807;
+             print 2 * 99999999999999999999 == 199999999999999999998; print 1e20 > fact(21);
+             print 99999999999999999999 + 0.5; print {99999999999999999999: 1, 1: 2};
+             print {1e20: \"x\"}[100000000000000000000]; print {9223372036854775808.0: 1};"
+        ));
+    }
+
+    #[test]
+    fn strings() {
+        test!(both(
+            r#"print "quote \" backslash \\ tab \t newline \n dollar \${x}";
+               let name = "wörld"; let n = 2;
+               print "hello ${name}, ${n} + 1 = ${n + 1} ${[n, "s"]} ${{}}";
+               print len(name); print substr(name, 1, 3); print split("a,b,,c", ",");
+               print split("héllo", ""); print upper(name); print lower("ÀB");
+               print "[" + trim("  x y \n") + "]"; print contains(name, "ö");
+               print contains(name, "x"); print replace("a-b-c", "-", "+");"#
+        ));
+    }
+
+    #[test]
+    fn collection_errors() {
+        let errors: Vec<_> = [
+            "let xs = [1]; xs[1];",
+            "let xs = [1]; xs[0 - 1] = 2;",
+            "let xs = [1]; xs[\"0\"];",
+            "let x = 1; x[0];",
+            "pop([]);",
+            "slice([1, 2], 1, 3);",
+            "len(1);",
+            "push([1]);",
+            "let m = {1: 2}; m[2];",
+            "let m = {}; m[[1]] = 2;",
+            "let xs = []; push(xs, xs);",
+            "let xs = [1]; xs[0] = [xs];",
+            "let xs = [1]; let m = {}; m[0] = xs; xs[0] = m;",
+            "let m = {}; m[1] = m;",
+            "let m = {}; let xs = [m]; m[\"xs\"] = xs;",
+            "print {{}: 1};",
+            "keys([1]);",
+            "substr(\"abc\", 2, 4);",
+            "upper(1);",
+            "split(\"a\", null);",
+        ]
+        .into_iter()
+        .map(|program| {
+            VM::new(vec![])
+                .eval(&parse(program))
+                .unwrap_err()
+                .to_string()
+        })
+        .collect();
+        test!(errors);
+    }
+
+    #[test]
+    fn short_circuit() {
+        test!(both(
+            "let x = 0; print x != 0 && 10 / x > 1;
+             fn loud(v) { print v; return v; }
+             print loud(0) && loud(1); print loud(2) || loud(3);
+             print loud(\"\") || loud(null); print 1 && \"yes\"; print null || [];"
+        ));
+    }
+
+    #[test]
+    fn assign_to_undefined() {
+        let mut vm = VM::new(vec![]);
+        let err = vm.eval(&parse("{ let x = 1; } x = 2;")).unwrap_err();
+        test!(err.to_string());
+    }
+
+    #[test]
+    fn exit_unwinds() {
+        let mut vm = VM::new(vec![]);
+        let res = vm.eval(&parse(
+            "fn stop(n) { while true { exit n; } } print 1; print stop(7); print 2;",
+        ));
+        assert_eq!(res.unwrap(), ExecutionResult::Exited(7));
+        test!(String::from_utf8(vm.writer).unwrap());
+    }
+
+    #[test]
+    fn exit_code_out_of_range() {
+        let err = VM::new(vec![])
+            .eval(&parse("exit 4294967297;"))
+            .unwrap_err();
+        test!(err.to_string());
+    }
+
+    #[test]
+    fn max_call_depth() {
+        // The default depth fits in a main thread's stack, though not in the
+        // smaller one of a test thread.
+        let results = std::thread::Builder::new()
+            .stack_size(8 * 1024 * 1024)
+            .spawn(|| {
+                let program = parse(
+                    "fn forever(n) { if n >= 0 { while true { return [-forever(n + 1) * 2][0]; } } }
+                     forever(0);",
+                );
+                [VM::new(vec![]), VM::new(vec![]).with_max_call_depth(10)]
+                    .into_iter()
+                    .map(|mut vm| {
+                        let err = vm.eval(&program).unwrap_err().to_string();
+                        // The calls that were cut short don't leave any behind.
+                        assert_eq!(vm.depth, 0);
+                        err
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        test!(results);
+    }
+
+    #[test]
+    fn early_return() {
+        let mut vm = VM::new(vec![]);
+        vm.eval(&parse(
+            "fn fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); }
+             fn first_over(limit) { let i = 0; while true { { if i * i > limit { return i; } } i += 1; } }
+             fn nested(n) { if n > 0 { return nested(n - 1) + 1; } return 0; }
+             fn nothing() { if false { return 1; } }
+             print fib(15); print first_over(50); print nested(5); print nothing();",
+        ))
+        .unwrap();
+        test!(String::from_utf8(vm.writer).unwrap());
+    }
+
+    #[test]
+    fn closures() {
+        test!(both(
+            "fn make_counter() { let count = 0; return fn() { count += 1; return count; }; }
+             let a = make_counter(); let b = make_counter();
+             print a(); print a(); print b();
+
+             fn compose(f, g) { return fn(x) { return f(g(x)); }; }
+             fn adder(n) { return fn(x) { return x + n; }; }
+             let add_three = compose(adder(1), adder(2));
+             print add_three(10);
+
+             fn keep_below(limit, n, f) { if n < limit { f(n); keep_below(limit, n + 1, f); } }
+             let total = 0;
+             keep_below(5, 0, fn(n) { total += n; });
+             print total;
+
+             let fns = [adder(1), fn(x) { return x * 2; }];
+             print fns; print adder; print (fn(x) { return x; })(7);
+
+             let later = [];
+             for (let i = 0; i < 3; i++) { let j = i; push(later, fn() { return [i, j]; }); }
+             print later[0](); print later[2]();
+             { fn even(n) { if n == 0 { return true; } return !even(n - 1); } print even(4); }"
+        ));
+    }
+
+    #[test]
+    fn return_errors() {
+        let errors: Vec<_> = [
+            "return 1;",
+            "fn f(a) { return a; } f(1, 2);",
+            "let x = 1; x();",
+        ]
+        .into_iter()
+        .map(|program| {
+            VM::new(vec![])
+                .eval(&parse(program))
+                .unwrap_err()
+                .to_string()
+        })
+        .collect();
+        test!(errors);
+    }
+
+    #[test]
+    fn static_errors() {
+        let errors: Vec<_> = [
+            "print 1; fn f(a, a) { return a; }",
+            "print 1; fn f() { x = 1; let x = 2; }",
+        ]
+        .into_iter()
+        .map(|program| {
+            let mut vm = VM::new(vec![]);
+            let err = vm.eval(&parse(program)).unwrap_err().to_string();
+            assert!(vm.writer.is_empty(), "{program} ran before failing");
+            let compiled = bytecode::Compiler::default().compile(&parse(program));
+            assert_eq!(compiled.unwrap_err().to_string(), err);
+            err
+        })
+        .collect();
+        test!(errors);
+    }
+
+    #[test]
+    fn shadowing_in_closures() {
+        test!(both(
+            "fn f() {
+                 let x = 1; let get = fn() { return x; }; let x = 2;
+                 print get(); print x; x = 3; print get();
+             }
+             f();
+             fn g() { let x = 1; let h = fn() { return x; }; let x = 2; return h(); } print g();"
+        ));
+    }
+
+    #[test]
+    fn read_line() {
+        let mut vm = VM::new(vec![]).with_reader("first\r\nsecond\n".as_bytes());
+        let lines = [
+            vm.read_line().unwrap(),
+            vm.read_line().unwrap(),
+            vm.read_line().unwrap(),
+        ];
+        test!(lines);
+        assert_eq!(VM::new(vec![]).read_line().unwrap(), None);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let results: Vec<_> = [
+            (Overflow::Checked, "9223372036854775807 + 1"),
+            (Overflow::Checked, "1 / (2 - 2)"),
+            (Overflow::Checked, "7 / 2"),
+            (Overflow::Wrapping, "9223372036854775807 + 1"),
+            (Overflow::Saturating, "9223372036854775807 * 2"),
+        ]
+        .into_iter()
+        .map(|(overflow, program)| {
+            let Stmt::Expr(expr) = &parse(program)[0] else {
+                unreachable!()
+            };
+            match VM::new(vec![]).with_overflow(overflow).eval_value(expr) {
+                Ok(ExecutionResult::Completed(value)) => format!("{program} = {value}"),
+                Ok(exited) => format!("{program}: {exited:?}"),
+                Err(e) => format!("{program}: {e}"),
             }
-        });
+        })
+        .collect();
+        test!(results);
+    }
+
+    #[test]
+    fn no_crash() {
+        // Arbitrary programs can recurse as deep as the call depth allows,
+        // which needs more than the test thread's stack. They can also loop
+        // forever, which isn't a crash, so the test only waits a while for
+        // the search to finish.
+        let (done, finished) = mpsc::channel();
+        std::thread::Builder::new()
+            .stack_size(256 * 1024 * 1024)
+            .spawn(move || {
+                arbtest(|input| {
+                    let stmts: Vec<Stmt> = input.arbitrary()?;
+                    let mut vm = VM::new(vec![]);
+                    match vm.eval(&stmts) {
+                        Ok(_) => Ok(()),
+                        Err(_) => Err(arbitrary::Error::NotEnoughData),
+                    }
+                })
+                .run();
+                let _ = done.send(());
+            })
+            .unwrap();
+        match finished.recv_timeout(Duration::from_secs(1)) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("a program crashed the VM"),
+        }
     }
 }
@@ -0,0 +1,87 @@
+use std::process::Command;
+
+use insta_cmd::{assert_cmd_snapshot, get_cargo_bin};
+
+fn cli() -> Command {
+    Command::new(get_cargo_bin("ir"))
+}
+
+#[test]
+fn run() {
+    assert_cmd_snapshot!(cli().arg("run").arg("tests/programs/arith.ir"));
+}
+
+#[test]
+fn run_missing_file() {
+    assert_cmd_snapshot!(cli().arg("run").arg("tests/programs/missing.ir"));
+}
+
+#[test]
+fn run_syntax_error() {
+    assert_cmd_snapshot!(cli().arg("run").arg("tests/programs/syntax_error.ir"));
+}
+
+#[test]
+fn run_fib() {
+    assert_cmd_snapshot!(cli().arg("run").arg("tests/programs/fib.ir"));
+}
+
+#[test]
+fn run_unbounded_recursion() {
+    assert_cmd_snapshot!(cli().arg("run").arg("tests/programs/forever.ir"));
+}
+
+#[test]
+fn run_exit() {
+    assert_cmd_snapshot!(cli().arg("run").arg("tests/programs/exit.ir"));
+}
+
+#[test]
+fn tokens() {
+    assert_cmd_snapshot!(cli().arg("tokens").arg("tests/programs/print.ir"));
+}
+
+#[test]
+fn tokens_missing_file() {
+    assert_cmd_snapshot!(cli().arg("tokens").arg("tests/programs/missing.ir"));
+}
+
+#[test]
+fn ast() {
+    assert_cmd_snapshot!(cli().arg("ast").arg("tests/programs/arith.ir"));
+}
+
+#[test]
+fn optimize() {
+    assert_cmd_snapshot!(cli().arg("optimize").arg("tests/programs/arith.ir"));
+}
+
+#[test]
+fn bytecode() {
+    assert_cmd_snapshot!(cli().arg("bytecode").arg("tests/programs/print.ir"));
+}
+
+#[test]
+fn compile_and_exec() {
+    let tmp_dir = env!("CARGO_TARGET_TMPDIR");
+    std::fs::copy("tests/programs/arith.ir", format!("{tmp_dir}/arith.ir")).unwrap();
+    assert_cmd_snapshot!(cli()
+        .current_dir(tmp_dir)
+        .arg("compile")
+        .arg("arith.ir")
+        .arg("-o")
+        .arg("arith.irb"));
+    assert_cmd_snapshot!(cli().current_dir(tmp_dir).arg("exec").arg("arith.irb"));
+}
+
+#[test]
+fn exec_invalid_file() {
+    assert_cmd_snapshot!(cli().arg("exec").arg("tests/programs/arith.ir"));
+}
+
+#[test]
+fn repl() {
+    assert_cmd_snapshot!(cli()
+        .arg("repl")
+        .pass_stdin("let x = 2;\nprint x + 1;\nprint y;\n"));
+}